@@ -0,0 +1,79 @@
+//! ## Matrix multiplication benchmark
+//! Compares `Matrix::dot` against the naive triple loop (parallelised over the rows)
+//! on the data matrices built from the sample files in `audio/`.
+//!
+//! Run with `cargo bench --bench dot`.
+
+use std::{fs, hint::black_box, time::Instant};
+
+use cp1::{encryption::gen_key, matrix::FloatMatrix, text::vector_to_matrix};
+use rayon::prelude::*;
+
+const FILES: [&str; 4] = ["audio/a1s.wav", "audio/a2s.wav", "audio/a5s.wav", "audio/a10s.wav"];
+const KEY_SIZES: [usize; 3] = [8, 32, 128];
+
+/// The naive multiplication: every element of the result is the dot product of a row of
+/// `lhs` with a (strided) column of `rhs`.
+fn naive_dot(lhs: &FloatMatrix, rhs: &FloatMatrix) -> FloatMatrix {
+    let data = (0..lhs.n)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            (0..rhs.m).map(move |j| (0..lhs.m).map(|k| lhs[i][k] * rhs[k][j]).sum::<f32>())
+        })
+        .collect();
+    FloatMatrix::from_vec(lhs.n, rhs.m, data).unwrap()
+}
+
+/// Panics unless the products agree, up to the rounding of the sums taken in another order.
+fn check_same(naive: &FloatMatrix, blocked: &FloatMatrix) {
+    assert_eq!((naive.n, naive.m), (blocked.n, blocked.m));
+    for (x, y) in naive.data.iter().zip(&blocked.data) {
+        assert!((x - y).abs() <= 1e-4 * x.abs().max(1.0), "the blocked product is {y} instead of {x}");
+    }
+}
+
+/// Returns the best of three runs of `f` in milliseconds.
+fn time<R>(mut f: impl FnMut() -> R) -> f64 {
+    (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed().as_secs_f64() * 1000.0
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn main() {
+    println!(
+        "{:<16} {:>5} {:>10} {:>12} {:>12} {:>8}",
+        "file", "key", "columns", "naive (ms)", "dot (ms)", "speedup"
+    );
+
+    for file in FILES {
+        // Split the bytes of the file into nibbles just like the encryption does.
+        let nibbles = fs::read(file)
+            .unwrap()
+            .iter()
+            .flat_map(|&x| [(x >> 4) as u64, (x & 0xF) as u64])
+            .collect::<Vec<_>>();
+
+        for key_size in KEY_SIZES {
+            let key = gen_key(key_size, false).unwrap();
+            let data = vector_to_matrix(nibbles.clone(), key_size, |x| x as f32).unwrap();
+
+            let naive = time(|| naive_dot(&key, &data));
+            let blocked = time(|| key.dot(&data).unwrap());
+            check_same(&naive_dot(&key, &data), &key.dot(&data).unwrap());
+
+            println!(
+                "{:<16} {:>5} {:>10} {:>12.2} {:>12.2} {:>7.2}x",
+                file.trim_start_matches("audio/"),
+                key_size,
+                data.m,
+                naive,
+                blocked,
+                naive / blocked
+            );
+        }
+    }
+}
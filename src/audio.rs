@@ -1,173 +1,701 @@
-use std::{fs::File, io, path::Path};
-
-use wav::{BitDepth, Header};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
 
 use crate::{
-    encryption::{
-        dectrypt_matrix_with_key_direct, dectrypt_matrix_with_key_iterative,
-        encrypt_matrix_with_key,
+    channels::{decrypt_channels_stream, encrypt_channels_stream, ChannelSelection},
+    container::{is_container, ContainerHeader, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::{Error, Result},
+    files::Output,
+    metadata::WavMetadata,
+    riff::{
+        chunk_len, find_any_chunk, padded_len, parse_fmt, read_wav_chunks, read_wav_header, skip,
+        write_wav_chunks, write_wav_header, WavFormat, CHANNELS_CHUNK, SCRAMBLED_HEADER_CHUNK,
+        SEGMENTS_CHUNK, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM,
     },
-    matrix::FloatMatrix,
-    text::{matrix_to_vector, vector_to_matrix},
+    scramble::{decrypt_scrambled_stream, encrypt_scrambled_stream},
+    segments::{decrypt_segments_stream, encrypt_segments_stream, TimeRange},
+    stream::{read_full, Sections},
     SolutionMethod,
 };
 
 pub struct AudioContents {
-    header: Header,
-    data: Vec<i16>,
+    pub header: WavFormat,
+    pub data: BitDepth,
+}
+
+/// ## Bit depth
+/// The samples of a `.wav` file, of every channel one after the other.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BitDepth {
+    Eight(Vec<u8>),
+    Sixteen(Vec<i16>),
+    TwentyFour(Vec<i32>),
+    ThirtyTwo(Vec<i32>),
+    ThirtyTwoFloat(Vec<f32>),
+}
+
+/// ## Audio options
+/// How `.wav` files are encrypted.
+#[derive(Debug, Clone, Default)]
+pub struct AudioOptions {
+    /// Only encrypt the selected channels, each separately with its own slice of the key,
+    /// leaving the others playable.
+    pub channels: Option<ChannelSelection>,
+    /// Only encrypt these parts of the recording, leaving the rest playable (all of it if
+    /// there are none).
+    pub ranges: Vec<TimeRange>,
+    /// Store the encrypted samples as the samples of a file just as long as the original one,
+    /// which plays as noise (only in the modular mode).
+    pub scramble: bool,
+    /// Encrypt the metadata chunks (`LIST`, `cue `, ...) too, instead of copying them.
+    pub encrypt_metadata: bool,
 }
 
 /// ## Read audio file contents
 /// Reads the contents of an audio file to `AudioContents` struct.
 /// ### Limiatations:
-/// - can only read 16 bit `.wav` files.
-pub fn read_audio_file_contents(file_path: &str) -> io::Result<AudioContents> {
-    let mut file = File::open(Path::new(file_path))?;
-    let (header, data) = wav::read(&mut file)?;
-    let BitDepth::Sixteen(data) = data else {
-        panic!("The file is not 16 bit.")
+/// - can only read 8, 16, 24 and 32 bit integer and 32 bit float `.wav` files.
+pub fn read_audio_file_contents(file_path: &str) -> Result<AudioContents> {
+    let mut file = BufReader::new(File::open(Path::new(file_path))?);
+    read_audio_contents(&mut file).map_err(|e| match e {
+        Error::UnsupportedFormat(reason) => {
+            Error::UnsupportedFormat(format!("`{file_path}`: {reason}"))
+        }
+        e => e,
+    })
+}
+
+/// Same as `read_audio_file_contents`, but reads the `.wav` file from `reader`.
+pub fn read_audio_contents<R: Read + ?Sized>(reader: &mut R) -> Result<AudioContents> {
+    let (header, data_len) = read_wav_header(reader)?;
+    let width = sample_width(&header)?;
+
+    let mut bytes = Vec::new();
+    reader.take(data_len).read_to_end(&mut bytes)?;
+    let samples = bytes.chunks_exact(width);
+    let data = match (header.audio_format, width) {
+        (WAVE_FORMAT_IEEE_FLOAT, _) => BitDepth::ThirtyTwoFloat(
+            samples.map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect(),
+        ),
+        (_, 1) => BitDepth::Eight(bytes),
+        (_, 2) => BitDepth::Sixteen(samples.map(|x| i16::from_le_bytes([x[0], x[1]])).collect()),
+        // Shift the 24 bits to the top and back, so the sign is extended.
+        (_, 3) => BitDepth::TwentyFour(
+            samples.map(|x| i32::from_le_bytes([0, x[0], x[1], x[2]]) >> 8).collect(),
+        ),
+        _ => BitDepth::ThirtyTwo(
+            samples.map(|x| i32::from_le_bytes(x.try_into().unwrap())).collect(),
+        ),
     };
+
     Ok(AudioContents { header, data })
 }
 
-/// Writes the given 16-bit vector of integers into a `.wav` file.
-fn write_audio_file_vector(
-    file_path: &str,
-    file_contents: &AudioContents,
-    data: Vec<i16>,
-    header: Option<usize>,
-) -> io::Result<()> {
-    let mut file = File::create(Path::new(file_path)).unwrap();
+/// Writes the given samples into a `.wav` file with the given header.
+pub fn write_audio_file_vector(file_path: &str, header: WavFormat, data: BitDepth) -> Result<()> {
+    let mut file = BufWriter::new(File::create(Path::new(file_path))?);
+
+    write_audio_contents(&mut file, &AudioContents { header, data })?;
+    file.flush()?;
+
+    Ok(())
+}
 
-    let mut data = data;
+/// Writes the audio as a `.wav` file to `writer`.
+pub fn write_audio_contents<W: Write + ?Sized>(writer: &mut W, audio: &AudioContents) -> Result<()> {
+    let bytes = match &audio.data {
+        BitDepth::Eight(data) => data.clone(),
+        BitDepth::Sixteen(data) => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        BitDepth::TwentyFour(data) => data.iter().flat_map(|x| x.to_le_bytes()[..3].to_vec()).collect(),
+        BitDepth::ThirtyTwo(data) => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        BitDepth::ThirtyTwoFloat(data) => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+    };
+    write_wav_header(writer, &audio.header, bytes.len() as u64)?;
+    writer.write_all(&bytes)?;
+    if bytes.len() % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    Ok(())
+}
 
-    // If a header is provided, chop it up into 16 bit integers and preppend it to the stream.
-    if let Some(header) = header {
-        data = [
-            vec![((header >> 16) & 0xFFFF) as i16, (header & 0xFFFF) as i16],
-            data,
-        ]
-        .concat()
+/// The number of bytes of one sample of one channel.
+pub(crate) fn sample_width(header: &WavFormat) -> Result<usize> {
+    match (header.audio_format, header.bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) => Ok(1),
+        (WAVE_FORMAT_PCM, 16) => Ok(2),
+        (WAVE_FORMAT_PCM, 24) => Ok(3),
+        (WAVE_FORMAT_PCM, 32) | (WAVE_FORMAT_IEEE_FLOAT, 32) => Ok(4),
+        (WAVE_FORMAT_IEEE_FLOAT, bits) => Err(Error::UnsupportedFormat(format!(
+            "{bits} bit float wav files are not supported"
+        ))),
+        (WAVE_FORMAT_PCM, bits) => Err(Error::UnsupportedFormat(format!(
+            "{bits} bit wav files are not supported"
+        ))),
+        (format, _) => Err(Error::UnsupportedFormat(format!(
+            "wav files with format {format} are not supported"
+        ))),
     }
+}
 
-    wav::write(file_contents.header, &BitDepth::Sixteen(data), &mut file)?;
+/// The number of bytes of a frame (one sample of every channel). The `data_len` bytes of
+/// samples have to be whole frames.
+pub(crate) fn frame_len(header: &WavFormat, width: usize, data_len: u64) -> Result<u64> {
+    let frame_len = (width * header.channel_count as usize) as u64;
+    if frame_len == 0 || !data_len.is_multiple_of(frame_len) {
+        return Err(Error::UnsupportedFormat("the data chunk does not hold whole frames".to_string()));
+    }
+    Ok(frame_len)
+}
+
+/// The format of the encrypted file, which holds the container as 16-bit integers. 16-bit files
+/// keep their format.
+fn encrypted_format(header: &WavFormat) -> WavFormat {
+    if header.audio_format == WAVE_FORMAT_PCM && header.bits_per_sample == 16 {
+        return *header;
+    }
+
+    WavFormat {
+        audio_format: WAVE_FORMAT_PCM,
+        channel_count: header.channel_count,
+        sampling_rate: header.sampling_rate,
+        bytes_per_second: header.sampling_rate * 2 * header.channel_count as u32,
+        bytes_per_sample: 2 * header.channel_count,
+        bits_per_sample: 16,
+    }
+}
+
+/// Serializes the `fmt ` chunk of the original file as it is (its length as a `u16`, then its
+/// contents), so the container does not rely on the header of the encrypted file and the
+/// decrypted file gets back any extension of the format.
+pub(crate) fn header_to_metadata(fmt: &[u8]) -> Vec<u8> {
+    // `read_wav_chunks` does not read longer `fmt ` chunks.
+    let len = u16::try_from(fmt.len()).unwrap();
+    [len.to_be_bytes().as_slice(), fmt].concat()
+}
 
+/// The inverse of `header_to_metadata`. Returns the format, the contents of the `fmt ` chunk and
+/// the rest of the metadata (the segment map or the channel layout, if only some samples were
+/// encrypted).
+pub(crate) fn header_from_metadata(metadata: &[u8]) -> Result<(WavFormat, &[u8], &[u8])> {
+    let malformed = || Error::MalformedCiphertext("the container does not describe a wav file".to_string());
+    let len = metadata.get(..2).ok_or_else(malformed)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let fmt = metadata.get(2..2 + len).ok_or_else(malformed)?;
+    let format = parse_fmt(fmt).map_err(|_| malformed())?;
+
+    Ok((format, fmt, &metadata[2 + len..]))
+}
+
+/// The value of a silent byte of samples: the middle of the range for 8-bit samples, which are
+/// unsigned, and zero for the others.
+pub(crate) fn silence(format: &WavFormat, width: usize) -> u8 {
+    if format.audio_format == WAVE_FORMAT_PCM && width == 1 {
+        0x80
+    } else {
+        0
+    }
+}
+
+/// ## Side chunk
+/// The container of a file where only some of the samples were encrypted (time ranges or
+/// channels): the `data` chunk holds the samples, with silence in place of the encrypted ones,
+/// and a chunk after it holds the container.
+pub(crate) struct SideChunk {
+    /// Where the samples start, if the reader can seek; otherwise they are in `samples`.
+    data_start: Option<u64>,
+    samples: Vec<u8>,
+    pub data_len: u64,
+    /// The id of the chunk and the length of its contents.
+    pub id: [u8; 4],
+    pub len: u64,
+    pub header: ContainerHeader,
+}
+
+impl SideChunk {
+    /// Finds the chunk after the `data` chunk with one of the given ids and reads the header of
+    /// the container in it. `head` are the first bytes of the `data_len` bytes of samples,
+    /// `reader` has to be right after them. If it can not seek, the samples are kept in memory.
+    pub fn find<R: Read + Seek + ?Sized>(
+        reader: &mut R,
+        head: &[u8],
+        data_len: u64,
+        ids: &[[u8; 4]],
+    ) -> Result<Self> {
+        let data_start = reader.stream_position().ok().map(|position| position - head.len() as u64);
+        let mut samples = head.to_vec();
+        match data_start {
+            Some(data_start) => {
+                reader.seek(SeekFrom::Start(data_start + data_len))?;
+            }
+            None => copy_exact(reader, &mut samples, data_len - head.len() as u64)?,
+        }
+        skip(reader, data_len & 1)?;
+        let (id, len) = find_any_chunk(reader, ids)?.ok_or_else(|| {
+            Error::MalformedCiphertext("the wav file holds no encrypted samples".to_string())
+        })?;
+        let header = ContainerHeader::read_from(&mut reader.take(len))?;
+
+        Ok(SideChunk { data_start, samples, data_len, id, len, header })
+    }
+
+    /// The number of bytes of the body of the container.
+    pub fn body_len(&self) -> u64 {
+        self.len.saturating_sub(self.header.header_len() as u64)
+    }
+
+    /// Writes the decrypted file with the `fmt ` chunk `fmt`: `splice` gets the samples of the
+    /// `data` chunk and the body of the container and writes the decrypted samples, then the
+    /// metadata chunks after the `data` chunk are restored. `reader` has to be right after the
+    /// header of the container.
+    #[allow(clippy::too_many_arguments)]
+    pub fn restore<R, W, F>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        fmt: &[u8],
+        metadata: &WavMetadata,
+        key: &Key,
+        method: &SolutionMethod,
+        splice: F,
+    ) -> Result<Option<SolveReport>>
+    where
+        R: Read + Seek + ?Sized,
+        W: Write + ?Sized,
+        F: FnOnce(&mut dyn Read, &mut dyn Read, &mut W) -> Result<Option<SolveReport>>,
+    {
+        let trailing_len = metadata.restored_trailing_len(chunk_len(self.len))?;
+        write_wav_chunks(writer, fmt, &metadata.chunks, self.data_len, trailing_len)?;
+        let body_len = self.body_len();
+        let report = match self.data_start {
+            Some(data_start) => {
+                let body_start = reader.stream_position()?;
+                let sections = Sections::new(&mut *reader)?;
+                let report = splice(
+                    &mut sections.section(data_start, self.data_len),
+                    &mut sections.section(body_start, body_len),
+                    writer,
+                )?;
+                sections.into_inner().seek(SeekFrom::Start(body_start + body_len))?;
+                report
+            }
+            None => {
+                let mut body = reader.take(body_len);
+                let report = splice(&mut self.samples.as_slice(), &mut body, writer)?;
+                io::copy(&mut body, &mut io::sink())?;
+                report
+            }
+        };
+        if self.data_len % 2 == 1 {
+            writer.write_all(&[0])?;
+        }
+        skip(reader, self.len & 1)?;
+        metadata.write_restored_trailing(reader, writer, chunk_len(self.len), key, method)?;
+
+        Ok(report)
+    }
+}
+
+/// Copies exactly `len` bytes from `reader` to `writer`.
+pub(crate) fn copy_exact<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W, len: u64) -> Result<()> {
+    if io::copy(&mut reader.take(len), writer)? != len {
+        return Err(Error::UnsupportedFormat("the wav file is truncated".to_string()));
+    }
     Ok(())
 }
 
+/// Reverses the bytes of every `width`-byte sample, converting between the little-endian
+/// samples of `.wav` files and the big-endian bytes the cipher works on.
+pub struct SwapBytes<T> {
+    inner: T,
+    width: usize,
+    /// The bytes of a sample which is not complete yet (written) or not handed out yet (read).
+    pending: Vec<u8>,
+}
+
+impl<T> SwapBytes<T> {
+    pub fn new(inner: T, width: usize) -> Self {
+        SwapBytes { inner, width: width.max(1), pending: Vec::new() }
+    }
+}
+
+impl<W: Write> SwapBytes<W> {
+    /// Writes the last sample, if it never got complete, as if it was padded with zero bytes.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.pending.resize(self.width, 0);
+            self.pending.reverse();
+            self.inner.write_all(&self.pending)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for SwapBytes<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let whole = self.pending.len() - self.pending.len() % self.width;
+        for sample in self.pending[..whole].chunks_exact_mut(self.width) {
+            sample.reverse();
+        }
+        self.inner.write_all(&self.pending[..whole])?;
+        self.pending.drain(..whole);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for SwapBytes<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending.is_empty() && buf.len() < self.width {
+            let mut sample = vec![0; self.width];
+            let read = read_full(&mut self.inner, &mut sample)?;
+            sample.truncate(read);
+            if read == self.width {
+                sample.reverse();
+            }
+            self.pending = sample;
+        }
+        if !self.pending.is_empty() {
+            let read = buf.len().min(self.pending.len());
+            buf[..read].copy_from_slice(&self.pending[..read]);
+            self.pending.drain(..read);
+            return Ok(read);
+        }
+
+        // Read whole samples only, so that they stay aligned.
+        let whole = buf.len() - buf.len() % self.width;
+        let read = read_full(&mut self.inner, &mut buf[..whole])?;
+        for sample in buf[..read].chunks_exact_mut(self.width) {
+            sample.reverse();
+        }
+        Ok(read)
+    }
+}
+
+/// Encrypts the given audio file with the given key in the given mode, see `encrypt_audio_stream`.
+pub fn encrypt_audio_with_key(
+    file_path: &str,
+    key: &Key,
+    mode: Mode,
+    options: &AudioOptions,
+) -> Result<()> {
+    key.check_mode(mode)?;
+    let mut reader = BufReader::new(File::open(Path::new(file_path))?);
+
+    let mut out = Output::new(format!(
+        "{}-encrypted.wav",
+        file_path.strip_suffix(".wav").unwrap_or(file_path)
+    ));
+    encrypt_audio_stream(&mut reader, &mut out, key, mode, options)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Encrypts the audio with the given key in the given mode, see `encrypt_audio_stream`.
+pub fn encrypt_audio(
+    audio: &AudioContents,
+    key: &Key,
+    mode: Mode,
+    options: &AudioOptions,
+) -> Result<AudioContents> {
+    let mut wav = Vec::new();
+    write_audio_contents(&mut wav, audio)?;
+
+    let mut encrypted = Vec::new();
+    encrypt_audio_stream(&mut io::Cursor::new(wav), &mut encrypted, key, mode, options)?;
+
+    read_audio_contents(&mut encrypted.as_slice())
+}
+
 /// ### Encryption:
-/// 1. reade the file
-/// 2. convert to 2-digit (decimal) arithmetic
-///    - split the data into nibbles (4-bit integers)
-/// 3. encrypt with the key matrix
-/// 4. split the resulting 32-bit numbers into 16-bit numbers
-/// 5. store the resulting integers into an encrypted audio file
-pub fn encrypt_audio_with_key(file_path: &str, key: &FloatMatrix) -> io::Result<()> {
-    let audio = read_audio_file_contents(file_path)?;
-
-    let two_digit = audio
-        .data
-        .iter()
-        .flat_map(|&num| {
-            [
-                ((num >> 12) & 0xF) as usize,
-                ((num >> 8) & 0xF) as usize,
-                ((num >> 4) & 0xF) as usize,
-                ((num >> 0) & 0xF) as usize,
-            ]
-        })
-        .collect::<Vec<_>>();
-    let data_length = two_digit.len();
-    // println!("2-digit:\n{:?}", &two_digit[..100]);
-
-    let data = vector_to_matrix(two_digit, key.n, |x| x as f32);
-    // println!("data:\n{}", data);
-
-    let encrypted = encrypt_matrix_with_key(key, &data);
-    // println!("floats:\n{:?}", &encrypted.transpose().data[0][..100]);
-
-    let vec = matrix_to_vector(encrypted.transpose(), |x| x.to_bits() as usize);
-    // println!("bits of floats:\n{:?}", &vec[..100]);
-
-    let data = vec
-        .iter()
-        .flat_map(|&x| [((x >> 16) & 0xFFFF) as i16, (x & 0xFFFF) as i16])
-        .collect::<Vec<_>>();
-    // println!("slplit:\n{:?}", &vec[..100]);
-
-    write_audio_file_vector(
-        format!("{}-encrypted.wav", file_path.strip_suffix(".wav").unwrap()).as_str(),
-        &audio,
-        data,
-        Some(data_length),
+/// 1. reade the header of the file
+/// 2. split the samples (8, 16, 24 or 32-bit) into big-endian bytes
+/// 3. encrypt with the key matrix in the given mode into a container, which also holds
+///    the format of the file
+///    - float: split the data into nibbles (4-bit integers) and multiply by the key
+///    - modular: multiply the bytes by the key over `Z_257`
+///    - if the channels are selected, only their samples, every channel separately with its
+///      own slice of the key, leaving the others playable (see `encrypt_channels_stream`)
+///    - if time ranges are given, only their samples, leaving the rest of the file playable
+///      (see `encrypt_segments_stream`)
+///    - if scrambling, store the encrypted samples as the samples of a file just as long
+///      (see `encrypt_scrambled_stream`)
+/// 4. glue pairs of bytes of the container into 16-bit integers
+/// 5. store the resulting integers into an encrypted audio file, along with the metadata chunks
+///    of the original one, copied or encrypted (see `WavMetadata`)
+///
+/// The samples are encrypted block by block while they are read.
+pub fn encrypt_audio_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    key: &Key,
+    mode: Mode,
+    options: &AudioOptions,
+) -> Result<()> {
+    // 1. reade the header of the file
+    let wav = read_wav_chunks(reader)?;
+    let (format, data_len) = (wav.format, wav.data_len);
+    let width = sample_width(&format)?;
+    frame_len(&format, width, data_len)?;
+    let layouts = [options.channels.is_some(), !options.ranges.is_empty(), options.scramble];
+    if layouts.iter().filter(|&&layout| layout).count() > 1 {
+        return Err(Error::InvalidArgument(
+            "selected channels, time ranges and scrambling can not be combined".to_string(),
+        ));
+    }
+    let metadata = WavMetadata {
+        chunks: wav.chunks,
+        trailing_len: wav.trailing_len,
+        encrypted: options.encrypt_metadata,
+    };
+    if options.scramble {
+        return encrypt_scrambled_stream(reader, writer, &wav.fmt, data_len, &metadata, key, mode);
+    }
+    if !options.ranges.is_empty() {
+        return encrypt_segments_stream(reader, writer, &wav.fmt, data_len, &metadata, &options.ranges, key, mode);
+    }
+
+    if let Some(channels) = &options.channels {
+        return encrypt_channels_stream(reader, writer, &wav.fmt, data_len, &metadata, channels, key, mode);
+    }
+
+    let header = ContainerHeader::new(PayloadType::Audio, Some(data_len), header_to_metadata(&wav.fmt), key, mode)?;
+    let container_len = header
+        .container_len()
+        .ok_or_else(|| Error::UnsupportedFormat("the wav file is too long".to_string()))?;
+    // The last sample is padded with a zero byte, which the container ignores.
+    write_wav_chunks(
+        writer,
+        &encrypted_format(&format).to_fmt(),
+        &metadata.leading_chunks(key, mode)?,
+        padded_len(container_len),
+        metadata.stored_trailing_len(key, mode)?,
     )?;
 
+    // 2. split the samples into bytes, 3. encrypt them and 4. glue the bytes of the container
+    //    into 16-bit integers
+    let mut out = SwapBytes::new(&mut *writer, 2);
+    let mut samples = SwapBytes::new(reader.take(data_len), width);
+    header.seal(key, &mut samples, &mut out)?;
+    out.finish()?;
+
+    // 5. copy the metadata after the samples
+    skip(reader, data_len & 1)?;
+    metadata.write_trailing(reader, writer, key, mode)?;
+
     Ok(())
 }
 
-/// ### Decryption:
-/// 1. read the file
-/// 2. glue pairs of 16-bit integers into 32-bit floats
-/// 3. convert the vector to a matrix
-/// 4. decrypt with the key matrix
-/// 5. round the floats to the nearest 4-bit integers
-/// 6. convert to a vector
-/// 7. glue the quadruples of 4-bit integers back to 16-bit integes
-/// 8. store the resulting integers into a decrypted audio file
+/// Decrypts the given audio file with the given key, see `decrypt_audio_stream`.
 pub fn decrypt_audio_with_key(
     file_path: &str,
-    key: &FloatMatrix,
-    method: SolutionMethod,
-) -> io::Result<()> {
-    // 1. read the file
-    let audio = read_audio_file_contents(file_path)?;
-
-    // 2. glue pairs of 16-bit integers into 32-bit floats
-    let float_bits = (0..audio.data.len())
-        .step_by(2)
-        .map(|i| ((audio.data[i] as u16 as usize) << 16) | (audio.data[i + 1] as u16 as usize))
-        .collect::<Vec<_>>();
-    let data_length = float_bits[0];
-    // println!("32 bit float vector:\n{:?}", &vec[..100]);
-
-    // 3. convert the vector to a matrix
-    let data = vector_to_matrix(float_bits[1..].to_vec(), key.n, |x| f32::from_bits(x as u32));
-    // Can't print the matrix since it's veeeeery large.
-    // println!("floats:\n{:?}", &data.transpose().data[0][..100]);
-
-    // 4. decrypt with the key matrix
-    let decrypted = if let SolutionMethod::Iterative(iterations) = method {
-        dectrypt_matrix_with_key_iterative(key, data, iterations)
-    } else {
-        dectrypt_matrix_with_key_direct(key, data)
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    let mut reader = BufReader::new(File::open(Path::new(file_path))?);
+
+    let mut out = Output::new(format!(
+        "{}-decrypted.wav",
+        file_path.strip_suffix(".wav").unwrap_or(file_path)
+    ));
+    let report = decrypt_audio_stream(&mut reader, &mut out, key, method)?;
+    out.flush()?;
+
+    Ok(report)
+}
+
+/// Decrypts the audio with the given key, see `decrypt_audio_stream`.
+pub fn decrypt_audio(
+    audio: &AudioContents,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<AudioContents> {
+    let mut wav = Vec::new();
+    write_audio_contents(&mut wav, audio)?;
+
+    let mut decrypted = Vec::new();
+    decrypt_audio_stream(&mut io::Cursor::new(wav), &mut decrypted, key, method)?;
+
+    read_audio_contents(&mut decrypted.as_slice())
+}
+
+/// ### Decryption:
+/// 1. read the header of the file
+/// 2. split the 16-bit integers into the bytes of the container
+/// 3. check the key against the container and decrypt with the key matrix
+/// 4. glue the bytes back to samples of the original bit depth
+/// 5. store the resulting samples into a decrypted audio file with the original format
+/// 6. restore the metadata chunks of the original file
+///
+/// The samples are decrypted block by block while they are read. Files where only some time
+/// ranges or channels were encrypted are only decrypted without keeping their samples in memory
+/// if `reader` can seek, see `SideChunk`.
+///
+/// Returns the report of the iterative method (`None` for the direct one). The metadata chunks
+/// are not counted in it.
+pub fn decrypt_audio_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    // 1. read the header of the file
+    let armor = read_wav_chunks(reader)?;
+    let data_len = armor.data_len;
+    let scrambled = armor.chunks.iter().find(|chunk| chunk.id == SCRAMBLED_HEADER_CHUNK).cloned();
+    let metadata = WavMetadata::restore(armor.chunks, armor.trailing_len, key, method)?;
+    if let Some(chunk) = scrambled {
+        return decrypt_scrambled_stream(reader, writer, &chunk.data, data_len, &metadata, key, method);
+    }
+
+    // 2. split the 16-bit integers into the bytes of the container
+    let mut head = [0; 4];
+    let read = read_full(&mut reader.take(data_len), &mut head)?;
+    if !is_container(&[head[1], head[0], head[3], head[2]][..read]) {
+        // Only some time ranges or channels were encrypted, the samples are the original ones.
+        let side = SideChunk::find(reader, &head[..read], data_len, &[SEGMENTS_CHUNK, CHANNELS_CHUNK])?;
+        return match side.id {
+            CHANNELS_CHUNK => decrypt_channels_stream(reader, writer, &side, &metadata, key, method),
+            _ => decrypt_segments_stream(reader, writer, &side, &metadata, key, method),
+        };
+    }
+    let mut samples = SwapBytes::new(head[..read].chain(reader.take(data_len - read as u64)), 2);
+    let header = ContainerHeader::read_from(&mut samples)?;
+    header.check_payload(PayloadType::Audio)?;
+    let (format, fmt, rest) = header_from_metadata(&header.metadata)?;
+    if !rest.is_empty() {
+        return Err(Error::MalformedCiphertext("the container does not describe a wav file".to_string()));
+    }
+    let width = sample_width(&format)?;
+    let length = header
+        .length
+        .filter(|length| length.is_multiple_of(width as u64))
+        .ok_or_else(|| Error::MalformedCiphertext("invalid length header".to_string()))?;
+    header.check_decryptable(key, method)?;
+    write_wav_chunks(writer, fmt, &metadata.chunks, length, metadata.restored_trailing_len(0)?)?;
+
+    // 3. decrypt with the key matrix and 4. glue the bytes back to samples
+    let mut out = SwapBytes::new(&mut *writer, width);
+    let report = header.open(key, method, &mut samples, &mut out)?;
+    out.finish()?;
+    // The `data` chunk is padded to an even length.
+    if length % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    // 6. copy the metadata after the samples
+    io::copy(&mut samples, &mut io::sink())?;
+    drop(samples);
+    skip(reader, data_len & 1)?;
+    metadata.write_restored_trailing(reader, writer, 0, key, method)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::{gen_key, gen_modular_key, Key, Precision},
+        riff::Chunk,
     };
-    // Very large matrix here aswell.
-
-    // 5. round the floats to the nearest 4-bit integers
-    let decrypted = decrypted.transpose().round(0);
-    // println!("matrix:\n{decrypted}");
-
-    // 6. convert to a vector
-    let raw_vec = matrix_to_vector(decrypted, |x| x as u8 as usize);
-    let truncated_vec = raw_vec[..data_length].to_vec();
-    // println!("2-digit arithmetic decrypted:\n{:?}", &vec[..100]);
-
-    // 7. glue the quadruples of 4-bit integers back to 16-bit integes
-    let sixteen = (0..truncated_vec.len())
-        .step_by(4)
-        .map(|i| {
-            ((truncated_vec[i + 0] << 12) | (truncated_vec[i + 1] << 8) | (truncated_vec[i + 2] << 4) | (truncated_vec[i + 3] << 0)) as i16
-        })
-        .collect::<Vec<_>>();
-    // println!("glued:\n{:?}", &sixteen[..25]);
-
-    // 8. store the resulting integers into a decrypted audio file
-    write_audio_file_vector(
-        format!("{}-decrypted.wav", file_path.strip_suffix(".wav").unwrap()).as_str(),
-        &audio,
-        sixteen,
-        None,
-    )?;
 
-    Ok(())
+    /// A stereo 8 kHz `.wav` file with the given `fmt ` chunk, a `LIST` chunk and 0.1 s of
+    /// samples.
+    fn wav(fmt: &[u8]) -> Vec<u8> {
+        let format = parse_fmt(fmt).unwrap();
+        let data_len = 800 * format.bytes_per_sample as u64;
+        let mut wav = Vec::new();
+        let list = Chunk { id: *b"LIST", data: b"INFOtest".to_vec() };
+        write_wav_chunks(&mut wav, fmt, &[list], data_len, 0).unwrap();
+        wav.extend((0..data_len).map(|i| (i * 7 % 251) as u8));
+        wav
+    }
+
+    fn roundtrip(wav: &[u8], key: &Key, mode: Mode, options: &AudioOptions) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt_audio_stream(&mut io::Cursor::new(wav), &mut encrypted, key, mode, options).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_audio_stream(&mut io::Cursor::new(encrypted), &mut decrypted, key, &SolutionMethod::Direct).unwrap();
+        decrypted
+    }
+
+    #[test]
+    fn extended_formats_decrypt_byte_identical() {
+        // cbSize = 2 with two more bytes
+        let mut short = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 2,
+            sampling_rate: 8000,
+            bytes_per_second: 32000,
+            bytes_per_sample: 4,
+            bits_per_sample: 16,
+        }
+        .to_fmt();
+        short.extend_from_slice(&[2, 0, 0xAB, 0xCD]);
+        // WAVE_FORMAT_EXTENSIBLE, 24-bit PCM
+        let mut extensible = WavFormat {
+            audio_format: 0xFFFE,
+            channel_count: 2,
+            sampling_rate: 8000,
+            bytes_per_second: 48000,
+            bytes_per_sample: 6,
+            bits_per_sample: 24,
+        }
+        .to_fmt();
+        extensible.extend_from_slice(&[22, 0, 24, 0, 3, 0, 0, 0, 1, 0]);
+        extensible.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71]);
+
+        let key = Key::Float(gen_key(8, false).unwrap());
+        let modular = Key::Modular(gen_modular_key(8).unwrap());
+        let ranges = AudioOptions { ranges: vec!["0.02-0.05".parse().unwrap()], ..AudioOptions::default() };
+        let scramble = AudioOptions { scramble: true, ..AudioOptions::default() };
+        for fmt in [short, extensible] {
+            let wav = wav(&fmt);
+            let float = Mode::Float(Precision::Single);
+            assert_eq!(roundtrip(&wav, &key, float, &AudioOptions::default()), wav);
+            assert_eq!(roundtrip(&wav, &key, float, &ranges), wav);
+            assert_eq!(roundtrip(&wav, &modular, Mode::Modular, &scramble), wav);
+        }
+    }
+
+    #[test]
+    fn partial_frames_are_rejected() {
+        // 16-bit stereo, 7.5 frames
+        let fmt = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 2,
+            sampling_rate: 44100,
+            bytes_per_second: 176400,
+            bytes_per_sample: 4,
+            bits_per_sample: 16,
+        }
+        .to_fmt();
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, &fmt, &[], 30, 0).unwrap();
+        wav.extend_from_slice(&[1; 30]);
+
+        let key = Key::Float(gen_key(4, false).unwrap());
+        let encrypt = |options: &AudioOptions| {
+            let mode = Mode::Float(Precision::Single);
+            encrypt_audio_stream(&mut io::Cursor::new(&wav), &mut Vec::new(), &key, mode, options)
+        };
+        // The same check with and without time ranges
+        let ranges = AudioOptions { ranges: vec!["0-0.0001".parse().unwrap()], ..AudioOptions::default() };
+        for options in [AudioOptions::default(), ranges] {
+            assert!(matches!(encrypt(&options), Err(Error::UnsupportedFormat(e)) if e.contains("frames")));
+        }
+    }
 }
@@ -0,0 +1,111 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    container::{ContainerHeader, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::Result,
+    files::Output,
+    SolutionMethod,
+};
+
+/// The extension of encrypted binary files and of decrypted files without one.
+pub const BINARY_EXTENSION: &str = "bin";
+
+/// Encrypts the given file, whatever its contents, with the given key in the given mode.
+/// The result is stored next to it as `<name>-encrypted.bin`.
+pub fn encrypt_binary_with_key(file_path: &str, key: &Key, mode: Mode) -> Result<()> {
+    key.check_mode(mode)?;
+    let file = File::open(Path::new(file_path))?;
+    let length = file.metadata()?.len();
+
+    let mut out = Output::new(format!(
+        "{}-encrypted.{BINARY_EXTENSION}",
+        strip_extension(file_path)
+    ));
+    encrypt_binary_stream(
+        &mut BufReader::new(file),
+        Some(length),
+        file_extension(file_path),
+        &mut out,
+        key,
+        mode,
+    )?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Decrypts the given encrypted binary file with the given key. The result is stored next to
+/// it as `<name>-decrypted.<extension of the original file>`. Returns the report of the
+/// iterative method (`None` for the direct one).
+pub fn decrypt_binary_with_key(
+    file_path: &str,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    let mut reader = BufReader::new(File::open(Path::new(file_path))?);
+
+    let header = read_binary_header(&mut reader)?;
+    let mut out = Output::new(format!(
+        "{}-decrypted.{}",
+        strip_extension(file_path),
+        binary_extension(&header)
+    ));
+    let report = header.open(key, method, &mut reader, &mut out)?;
+    out.flush()?;
+
+    Ok(report)
+}
+
+/// ### Encryption:
+/// 1. encrypt the bytes `reader` yields (`length` of them, if known) with the key matrix in the
+///    given mode into a container, which also holds the extension of the original file (if any)
+/// 2. the bytes of the container are the encrypted file
+pub fn encrypt_binary_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    length: Option<u64>,
+    extension: Option<&str>,
+    writer: &mut W,
+    key: &Key,
+    mode: Mode,
+) -> Result<()> {
+    let metadata = extension.unwrap_or_default().as_bytes().to_vec();
+    let header = ContainerHeader::new(PayloadType::Binary, length, metadata, key, mode)?;
+
+    header.seal(key, reader, writer)
+}
+
+/// ### Decryption:
+/// 1. parse the container (this function), which leaves `reader` at the encrypted numbers
+/// 2. check the key against it and decrypt with the key matrix (`ContainerHeader::open`)
+pub fn read_binary_header<R: Read + ?Sized>(reader: &mut R) -> Result<ContainerHeader> {
+    let header = ContainerHeader::read_from(reader)?;
+    header.check_payload(PayloadType::Binary)?;
+    Ok(header)
+}
+
+/// The extension of the original file (`bin` if it had none).
+pub fn binary_extension(header: &ContainerHeader) -> String {
+    // Only trust extensions which can not escape the directory of the output.
+    String::from_utf8(header.metadata.clone())
+        .ok()
+        .filter(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| BINARY_EXTENSION.to_string())
+}
+
+/// The extension of the file, if it has one.
+pub fn file_extension(file_path: &str) -> Option<&str> {
+    Path::new(file_path).extension().and_then(|ext| ext.to_str())
+}
+
+/// The path of the file without its extension.
+fn strip_extension(file_path: &str) -> &str {
+    match file_extension(file_path) {
+        Some(ext) => &file_path[..file_path.len() - ext.len() - 1],
+        None => file_path,
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+};
+
+use crate::{
+    audio::{header_from_metadata, header_to_metadata, sample_width, silence, SideChunk},
+    container::{ContainerHeader, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::{Error, Result},
+    metadata::WavMetadata,
+    riff::{chunk_len, parse_fmt, skip, write_chunk_header, write_wav_chunks, CHANNELS_CHUNK},
+    stream::{bytes_to_numbers, encrypted_numbers, numbers_to_bytes, STREAM_COLUMNS},
+    text::{encrypt_bytes, Decryptor},
+    SolutionMethod,
+};
+
+/// The smallest slice of the key a channel is encrypted with (a 1x1 slice is the identity).
+const MIN_SLICE: usize = 2;
+
+/// ## Channel selection
+/// The channels of a `.wav` file to encrypt separately: `all` of them, or a list of channels
+/// (numbered from 1, e.g. `1,3`), leaving the others in the clear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelSelection {
+    All,
+    Only(Vec<u16>),
+}
+
+impl Display for ChannelSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelSelection::All => write!(f, "all"),
+            ChannelSelection::Only(channels) => {
+                let channels = channels.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", channels.join(","))
+            }
+        }
+    }
+}
+
+impl FromStr for ChannelSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "all" {
+            return Ok(ChannelSelection::All);
+        }
+
+        let channels = s
+            .split(',')
+            .map(|c| c.trim().parse::<u16>().ok().filter(|&c| c > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "invalid channels `{s}` (expected `all` or a list like `1,3`)"
+                ))
+            })?;
+        Ok(ChannelSelection::Only(channels))
+    }
+}
+
+/// ## Channel layout
+/// How the samples of the selected channels of a `.wav` file are stored in the container. The
+/// `i`-th encrypted channel is encrypted with the `i`-th block on the diagonal of the key (all
+/// of them `key.n / <encrypted channels>` in size), the rest stay in the `data` chunk.
+///
+/// The body holds groups of frames; in every group, the encrypted (big-endian) samples of every
+/// encrypted channel follow each other. Only the last group is shorter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelLayout {
+    /// The number of bytes of one sample of one channel.
+    pub width: usize,
+    /// Whether each channel is encrypted.
+    pub encrypted: Vec<bool>,
+}
+
+impl ChannelLayout {
+    /// The layout which encrypts the selected channels of `channel_count`.
+    pub fn new(selection: &ChannelSelection, channel_count: u16, width: usize) -> Result<Self> {
+        let mut encrypted = vec![false; channel_count as usize];
+        match selection {
+            ChannelSelection::All => encrypted.fill(true),
+            ChannelSelection::Only(channels) => {
+                for &c in channels {
+                    let channel = encrypted.get_mut(c as usize - 1).ok_or_else(|| {
+                        Error::InvalidArgument(format!(
+                            "there is no channel {c}, the file has {channel_count}"
+                        ))
+                    })?;
+                    *channel = true;
+                }
+            }
+        }
+        if !encrypted.contains(&true) {
+            return Err(Error::InvalidArgument("no channel to encrypt".to_string()));
+        }
+
+        Ok(ChannelLayout { width, encrypted })
+    }
+
+    /// One byte per channel, 1 if it is encrypted and 0 if it is in the clear.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encrypted.iter().map(|&e| e as u8).collect()
+    }
+
+    /// The inverse of `to_bytes`.
+    pub fn from_bytes(bytes: &[u8], width: usize) -> Result<Self> {
+        let encrypted = bytes
+            .iter()
+            .map(|&b| match b {
+                0 => Some(false),
+                1 => Some(true),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .filter(|encrypted| encrypted.contains(&true))
+            .ok_or_else(|| Error::MalformedCiphertext("invalid channel layout".to_string()))?;
+
+        Ok(ChannelLayout { width, encrypted })
+    }
+
+    /// The number of bytes of a frame (one sample of every channel).
+    fn frame_len(&self) -> usize {
+        self.width * self.encrypted.len()
+    }
+
+    /// The size of the slice of the key every encrypted channel gets.
+    fn slice_len(&self, key: &Key) -> Result<usize> {
+        let count = self.encrypted.iter().filter(|&&e| e).count();
+        let slice = key.n() / count;
+        if slice < MIN_SLICE {
+            return Err(Error::InvalidArgument(format!(
+                "a key of size {} is too small to give {count} channels their own slice",
+                key.n()
+            )));
+        }
+        Ok(slice)
+    }
+
+    /// The slice of the key for every encrypted channel.
+    fn channel_keys(&self, key: &Key) -> Result<Vec<Option<Key>>> {
+        let slice = self.slice_len(key)?;
+        let mut next = 0;
+        Ok(self
+            .encrypted
+            .iter()
+            .map(|&encrypted| {
+                encrypted.then(|| {
+                    next += slice;
+                    key.diagonal_block(next - slice, slice)
+                })
+            })
+            .collect())
+    }
+
+    /// The number of frames of a group, so that every channel of a full group fills whole
+    /// columns of its slice of the key.
+    fn group_frames(&self, key: &Key) -> Result<usize> {
+        Ok(self.slice_len(key)? * STREAM_COLUMNS)
+    }
+
+    /// The number of bytes of `frames` frames in the body.
+    fn group_len(&self, frames: usize, key: &Key, mode: Mode) -> Result<u64> {
+        let slice = self.slice_len(key)? as u64;
+        let samples = (frames * self.width) as u64;
+        let encrypted = encrypted_numbers(samples, mode, slice).unwrap() * (mode.bits() / 8) as u64;
+        Ok(encrypted * self.encrypted.iter().filter(|&&e| e).count() as u64)
+    }
+
+    /// Checks that the samples are whole frames and returns their number.
+    fn frames(&self, length: u64) -> Result<u64> {
+        if !length.is_multiple_of(self.frame_len() as u64) {
+            return Err(Error::UnsupportedFormat(
+                "the data chunk does not hold whole frames".to_string(),
+            ));
+        }
+        Ok(length / self.frame_len() as u64)
+    }
+
+    /// The number of bytes of the body for `length` bytes of samples.
+    pub fn body_len(&self, length: u64, key: &Key, mode: Mode) -> Result<u64> {
+        let frames = self.frames(length)?;
+        let group = self.group_frames(key)? as u64;
+        let last = (frames % group) as usize;
+        let mut len = frames / group * self.group_len(group as usize, key, mode)?;
+        if last > 0 {
+            len += self.group_len(last, key, mode)?;
+        }
+        Ok(len)
+    }
+
+    /// Encrypts the selected channels of the `length` bytes of (little-endian, interleaved)
+    /// samples `reader` yields and writes the body to `writer`.
+    pub fn seal<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        key: &Key,
+        mode: Mode,
+        length: u64,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<()> {
+        let keys = self.channel_keys(key)?;
+        let mut frames_left = self.frames(length)?;
+        let mut group = vec![0; self.group_frames(key)? * self.frame_len()];
+
+        while frames_left > 0 {
+            let frames = frames_left.min((group.len() / self.frame_len()) as u64) as usize;
+            let group = &mut group[..frames * self.frame_len()];
+            reader.read_exact(group).map_err(truncated)?;
+
+            for (channel, key) in keys.iter().enumerate() {
+                if let Some(key) = key {
+                    let samples = self.channel_samples(group, channel);
+                    writer.write_all(&numbers_to_bytes(&encrypt_bytes(&samples, key, mode)?, mode))?;
+                }
+            }
+            frames_left -= frames as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The inverse of `seal`: decrypts the body `body` yields and writes the `length` bytes of
+    /// (little-endian, interleaved) samples `samples` yields to `writer`, with the decrypted
+    /// samples in place of the ones of the selected channels.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open<S: Read + ?Sized, R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        key: &Key,
+        mode: Mode,
+        method: &SolutionMethod,
+        length: u64,
+        samples: &mut S,
+        body: &mut R,
+        writer: &mut W,
+    ) -> Result<Option<SolveReport>> {
+        // Every channel's slice of the key is made ready to decrypt once, for all the groups.
+        let mut decryptors = self
+            .channel_keys(key)?
+            .iter()
+            .map(|key| key.as_ref().map(|key| Decryptor::new(key, mode, method)).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        let slice = self.slice_len(key)? as u64;
+        let number_len = mode.bits() / 8;
+        let group_frames = self.group_frames(key)?;
+        let mut frames_left = self.frames(length)?;
+        let mut group = vec![0; group_frames * self.frame_len()];
+
+        while frames_left > 0 {
+            let frames = frames_left.min(group_frames as u64) as usize;
+            let samples_len = frames * self.width;
+            let group = &mut group[..frames * self.frame_len()];
+            samples.read_exact(group).map_err(truncated)?;
+
+            for (channel, decryptor) in decryptors.iter_mut().enumerate() {
+                if let Some(decryptor) = decryptor {
+                    let numbers = encrypted_numbers(samples_len as u64, mode, slice).unwrap() as usize;
+                    let mut bytes = vec![0; numbers * number_len];
+                    body.read_exact(&mut bytes).map_err(|_| {
+                        Error::MalformedCiphertext("the body is too short".to_string())
+                    })?;
+                    let decrypted = decryptor.decrypt(bytes_to_numbers(&bytes, mode), samples_len)?;
+                    self.set_channel_samples(group, channel, &decrypted);
+                }
+            }
+            writer.write_all(group)?;
+            frames_left -= frames as u64;
+        }
+
+        let report = decryptors.into_iter().flatten().filter_map(Decryptor::into_report).reduce(|mut report, other| {
+            report.merge(&other);
+            report
+        });
+        Ok(report)
+    }
+
+    /// Replaces the samples of the selected channels of the frames with `silence`.
+    fn silence(&self, frames: &mut [u8], silence: u8) {
+        for frame in frames.chunks_exact_mut(self.frame_len()) {
+            for (sample, &encrypted) in frame.chunks_exact_mut(self.width).zip(&self.encrypted) {
+                if encrypted {
+                    sample.fill(silence);
+                }
+            }
+        }
+    }
+
+    /// The big-endian samples of one channel of the frames.
+    fn channel_samples(&self, frames: &[u8], channel: usize) -> Vec<u8> {
+        frames
+            .chunks_exact(self.frame_len())
+            .flat_map(|frame| frame[channel * self.width..(channel + 1) * self.width].iter().rev())
+            .copied()
+            .collect()
+    }
+
+    /// The inverse of `channel_samples`.
+    fn set_channel_samples(&self, frames: &mut [u8], channel: usize, samples: &[u8]) {
+        let frame_len = self.frame_len();
+        for (frame, sample) in frames.chunks_exact_mut(frame_len).zip(samples.chunks_exact(self.width)) {
+            for (byte, &x) in frame[channel * self.width..(channel + 1) * self.width]
+                .iter_mut()
+                .zip(sample.iter().rev())
+            {
+                *byte = x;
+            }
+        }
+    }
+}
+
+/// The samples ended before the length in the header of the file.
+fn truncated(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::UnsupportedFormat("the wav file is truncated".to_string()),
+        _ => Error::from(e),
+    }
+}
+
+/// ### Encryption:
+/// 1. copy the samples, replacing the ones of the selected channels with silence, so the other
+///    channels stay playable
+/// 2. encrypt the samples of the selected channels with the key matrix in the given mode into a
+///    container, which also holds the format and the channel layout (see `ChannelLayout`)
+/// 3. store the container in a `cp1l` chunk after the `data` chunk, followed by the metadata
+///    chunks after the `data` chunk of the original file
+///
+/// `reader` has to be at the start of the `data_len` bytes of samples. If it can seek, the
+/// samples are read again for step 2, otherwise they are kept in memory until they are
+/// encrypted.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_channels_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    fmt: &[u8],
+    data_len: u64,
+    metadata: &WavMetadata,
+    selection: &ChannelSelection,
+    key: &Key,
+    mode: Mode,
+) -> Result<()> {
+    let format = parse_fmt(fmt)?;
+    let width = sample_width(&format)?;
+    let layout = ChannelLayout::new(selection, format.channel_count, width)?;
+    layout.frames(data_len)?;
+
+    let mut payload_metadata = header_to_metadata(fmt);
+    payload_metadata.extend_from_slice(&layout.to_bytes());
+    let header = ContainerHeader::new(PayloadType::AudioChannels, Some(data_len), payload_metadata, key, mode)?;
+    header.check_key(key)?;
+    let container_len = header.header_len() as u64 + layout.body_len(data_len, key, mode)?;
+    write_wav_chunks(
+        writer,
+        fmt,
+        &metadata.leading_chunks(key, mode)?,
+        data_len,
+        chunk_len(container_len) + metadata.stored_trailing_len(key, mode)?,
+    )?;
+
+    // 1. copy the samples, replacing the ones of the selected channels with silence
+    let data_start = reader.stream_position().ok();
+    let silence = silence(&format, width);
+    let mut samples = Vec::new();
+    let mut group = vec![0; layout.group_frames(key)? * layout.frame_len()];
+    let mut left = data_len;
+    while left > 0 {
+        let len = left.min(group.len() as u64) as usize;
+        let group = &mut group[..len];
+        reader.read_exact(group).map_err(truncated)?;
+        if data_start.is_none() {
+            samples.extend_from_slice(group);
+        }
+        layout.silence(group, silence);
+        writer.write_all(group)?;
+        left -= group.len() as u64;
+    }
+    if data_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    // 2. encrypt the samples of the selected channels and 3. store them after the `data` chunk
+    write_chunk_header(writer, &CHANNELS_CHUNK, container_len)?;
+    header.write_to(writer)?;
+    match data_start {
+        Some(data_start) => {
+            reader.seek(SeekFrom::Start(data_start))?;
+            layout.seal(key, mode, data_len, reader, writer)?;
+        }
+        None => layout.seal(key, mode, data_len, &mut samples.as_slice(), writer)?,
+    }
+    if container_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    skip(reader, data_len & 1)?;
+    metadata.write_trailing(reader, writer, key, mode)?;
+
+    Ok(())
+}
+
+/// ### Decryption:
+/// 1. find the `cp1l` chunk after the `data` chunk (see `SideChunk`)
+/// 2. check the key against the container and decrypt the samples of the selected channels with
+///    their slices of the key
+/// 3. put the samples back in place of the silence
+/// 4. store the samples into a decrypted audio file with the original format, along with the
+///    metadata chunks
+///
+/// `reader` has to be right after the header of the container.
+pub(crate) fn decrypt_channels_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    side: &SideChunk,
+    metadata: &WavMetadata,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    // 1. find the `cp1l` chunk after the `data` chunk
+    let header = &side.header;
+    header.check_payload(PayloadType::AudioChannels)?;
+    let (format, fmt, channels) = header_from_metadata(&header.metadata)?;
+    let layout = ChannelLayout::from_bytes(channels, sample_width(&format)?)?;
+    header.check_decryptable(key, method)?;
+    let body_len = layout.body_len(side.data_len, key, header.mode).ok();
+    if layout.encrypted.len() != format.channel_count as usize
+        || header.length != Some(side.data_len)
+        || body_len != Some(side.body_len())
+    {
+        return Err(Error::MalformedCiphertext("invalid channel layout".to_string()));
+    }
+
+    // 2. decrypt the samples of the selected channels and 3. put them back in place of the
+    //    silence, 4. store the samples
+    side.restore(reader, writer, fmt, metadata, key, method, |samples, body, writer| {
+        layout.open(key, header.mode, method, side.data_len, samples, body, writer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{decrypt_audio_stream, encrypt_audio_stream, AudioOptions},
+        encryption::{gen_key, Precision},
+        riff::{read_wav_chunks, WavFormat, WAVE_FORMAT_PCM},
+        stream::Unseekable,
+    };
+
+    #[test]
+    fn clear_channels_stay_in_place() {
+        // 8-bit with three channels and an odd number of frames, so the `data` chunk is padded.
+        let format = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 3,
+            sampling_rate: 8000,
+            bytes_per_second: 24000,
+            bytes_per_sample: 3,
+            bits_per_sample: 8,
+        };
+        let data_len = 3 * 5001;
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, &format.to_fmt(), &[], data_len, 0).unwrap();
+        let samples = (0..data_len).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        wav.extend_from_slice(&samples);
+        wav.push(0);
+
+        let key = Key::Float(gen_key(8, false).unwrap());
+        let mode = Mode::Float(Precision::Single);
+        let options = AudioOptions { channels: Some("1,3".parse().unwrap()), ..AudioOptions::default() };
+        let method = SolutionMethod::Direct;
+
+        let mut encrypted = Vec::new();
+        encrypt_audio_stream(&mut io::Cursor::new(&wav), &mut encrypted, &key, mode, &options).unwrap();
+        let mut buffered = Vec::new();
+        encrypt_audio_stream(&mut Unseekable(wav.as_slice()), &mut buffered, &key, mode, &options).unwrap();
+        assert_eq!(buffered, encrypted);
+
+        // The second channel is untouched, the others are silent.
+        let mut reader = encrypted.as_slice();
+        let armor = read_wav_chunks(&mut reader).unwrap();
+        assert_eq!(armor.fmt, format.to_fmt());
+        assert_eq!(armor.data_len, data_len);
+        for (frame, original) in reader[..data_len as usize].chunks_exact(3).zip(samples.chunks_exact(3)) {
+            assert_eq!(frame, [0x80, original[1], 0x80]);
+        }
+
+        let mut decrypted = Vec::new();
+        decrypt_audio_stream(&mut io::Cursor::new(&encrypted), &mut decrypted, &key, &method).unwrap();
+        assert_eq!(decrypted, wav);
+        let mut decrypted = Vec::new();
+        decrypt_audio_stream(&mut Unseekable(encrypted.as_slice()), &mut decrypted, &key, &method).unwrap();
+        assert_eq!(decrypted, wav);
+    }
+}
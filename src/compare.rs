@@ -0,0 +1,237 @@
+use std::{fmt::Display, io::Read};
+
+use crate::{
+    audio::sample_width,
+    error::{Error, Result},
+    files::open_input,
+    riff::{read_wav_header, WavFormat, WAVE_FORMAT_IEEE_FLOAT},
+    stream::read_full,
+};
+
+/// The number of samples compared at a time.
+const BLOCK_SAMPLES: usize = 4096;
+
+/// ## Comparison
+/// How close a decrypted file is to the original one, sample by sample (byte by byte for
+/// anything but `.wav` files). Samples missing from the shorter file count as silence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// Whether the samples of two `.wav` files were compared, rather than bytes.
+    pub audio: bool,
+    /// The number of samples of the longer file.
+    pub samples: u64,
+    /// The number of samples which are not the same bits.
+    pub mismatched: u64,
+    /// The largest absolute difference of two samples.
+    pub max_error: f64,
+    /// The mean absolute difference of the samples.
+    pub mean_error: f64,
+    /// The signal-to-noise ratio in dB, the power of the original samples over the power of
+    /// the differences (infinite if there are none).
+    pub snr: f64,
+}
+
+impl Comparison {
+    /// Whether the samples are exactly the same (the metadata chunks are not compared).
+    pub fn bit_exact(&self) -> bool {
+        self.mismatched == 0
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unit = if self.audio { "samples" } else { "bytes" };
+        writeln!(f, "{unit}: {}", self.samples)?;
+        writeln!(f, "mismatched {unit}: {}", self.mismatched)?;
+        writeln!(f, "max abs error: {}", self.max_error)?;
+        writeln!(f, "mean abs error: {}", self.mean_error)?;
+        writeln!(f, "SNR: {:.2} dB", self.snr)?;
+        write!(f, "bit-exact: {}", if self.bit_exact() { "yes" } else { "no" })
+    }
+}
+
+/// The running sums of a comparison.
+#[derive(Default)]
+struct Sums {
+    samples: u64,
+    mismatched: u64,
+    max_error: f64,
+    error: f64,
+    signal: f64,
+    noise: f64,
+}
+
+impl Sums {
+    fn add(&mut self, original: Option<(&[u8], f64)>, decrypted: Option<(&[u8], f64)>) {
+        let (original_bits, x) = original.unwrap_or((&[], 0.0));
+        let (decrypted_bits, y) = decrypted.unwrap_or((&[], 0.0));
+        self.samples += 1;
+        if original_bits == decrypted_bits {
+            self.signal += x * x;
+            return;
+        }
+
+        let error = (x - y).abs();
+        self.mismatched += 1;
+        self.max_error = self.max_error.max(error);
+        self.error += error;
+        self.signal += x * x;
+        self.noise += error * error;
+    }
+
+    fn finish(self, audio: bool) -> Comparison {
+        let snr = if self.noise == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (self.signal / self.noise).log10()
+        };
+        Comparison {
+            audio,
+            samples: self.samples,
+            mismatched: self.mismatched,
+            max_error: self.max_error,
+            mean_error: if self.samples == 0 { 0.0 } else { self.error / self.samples as f64 },
+            snr,
+        }
+    }
+}
+
+/// The value of a (little-endian) sample, with 8-bit samples centered around 0.
+fn sample_value(format: &WavFormat, sample: &[u8]) -> f64 {
+    match (format.audio_format, sample.len()) {
+        (WAVE_FORMAT_IEEE_FLOAT, _) => f32::from_le_bytes(sample.try_into().unwrap()) as f64,
+        (_, 1) => sample[0] as f64 - 128.0,
+        (_, 2) => i16::from_le_bytes([sample[0], sample[1]]) as f64,
+        (_, 3) => (i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8) as f64,
+        _ => i32::from_le_bytes(sample.try_into().unwrap()) as f64,
+    }
+}
+
+/// Compares the original file with the decrypted one (`-` for the standard input), see
+/// `compare_streams`.
+pub fn compare_files(original: &str, decrypted: &str) -> Result<Comparison> {
+    let (mut original, _) = open_input(original)?;
+    let (mut decrypted, _) = open_input(decrypted)?;
+
+    compare_streams(&mut original, &mut decrypted)
+}
+
+/// Compares the samples of two `.wav` files of the same format, or the bytes of any other two
+/// files. Both are read block by block.
+pub fn compare_streams<R: Read + ?Sized, S: Read + ?Sized>(original: &mut R, decrypted: &mut S) -> Result<Comparison> {
+    let mut original_head = [0; 4];
+    let mut decrypted_head = [0; 4];
+    let original_read = read_full(original, &mut original_head)?;
+    let decrypted_read = read_full(decrypted, &mut decrypted_head)?;
+    let mut original = original_head[..original_read].chain(original);
+    let mut decrypted = decrypted_head[..decrypted_read].chain(decrypted);
+
+    let is_wav = |head: &[u8]| head.starts_with(b"RIFF");
+    if !is_wav(&original_head[..original_read]) || !is_wav(&decrypted_head[..decrypted_read]) {
+        // Bytes are compared as they are, not centered like 8-bit samples.
+        return compare_samples(&mut original, u64::MAX, &mut decrypted, u64::MAX, 1, |byte| byte[0] as f64)
+            .map(|sums| sums.finish(false));
+    }
+
+    let (format, original_len) = read_wav_header(&mut original)?;
+    let (decrypted_format, decrypted_len) = read_wav_header(&mut decrypted)?;
+    let same = |f: &WavFormat| (f.audio_format, f.channel_count, f.bits_per_sample);
+    if same(&format) != same(&decrypted_format) {
+        return Err(Error::UnsupportedFormat(
+            "the files have different formats, so their samples can not be compared".to_string(),
+        ));
+    }
+    let width = sample_width(&format)?;
+
+    compare_samples(&mut original, original_len, &mut decrypted, decrypted_len, width, |sample| {
+        sample_value(&format, sample)
+    })
+    .map(|sums| sums.finish(true))
+}
+
+/// Compares `width`-byte samples of the first `len` bytes of both readers.
+fn compare_samples<R: Read + ?Sized, S: Read + ?Sized>(
+    original: &mut R,
+    original_len: u64,
+    decrypted: &mut S,
+    decrypted_len: u64,
+    width: usize,
+    value: impl Fn(&[u8]) -> f64,
+) -> Result<Sums> {
+    let mut original = original.take(original_len);
+    let mut decrypted = decrypted.take(decrypted_len);
+    let mut original_block = vec![0; BLOCK_SAMPLES * width];
+    let mut decrypted_block = vec![0; BLOCK_SAMPLES * width];
+    let mut sums = Sums::default();
+
+    loop {
+        let original_read = read_full(&mut original, &mut original_block)? / width;
+        let decrypted_read = read_full(&mut decrypted, &mut decrypted_block)? / width;
+        if original_read == 0 && decrypted_read == 0 {
+            break;
+        }
+
+        let original_samples = original_block[..original_read * width].chunks_exact(width);
+        let decrypted_samples = decrypted_block[..decrypted_read * width].chunks_exact(width);
+        let mut original_samples = original_samples.map(|sample| (sample, value(sample)));
+        let mut decrypted_samples = decrypted_samples.map(|sample| (sample, value(sample)));
+        for _ in 0..original_read.max(decrypted_read) {
+            sums.add(original_samples.next(), decrypted_samples.next());
+        }
+    }
+
+    Ok(sums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riff::{write_wav_chunks, WAVE_FORMAT_PCM};
+
+    /// A 16-bit mono `.wav` file with the given samples.
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let format = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 1,
+            sampling_rate: 8000,
+            bytes_per_second: 16000,
+            bytes_per_sample: 2,
+            bits_per_sample: 16,
+        };
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, &format.to_fmt(), &[], 2 * samples.len() as u64, 0).unwrap();
+        wav.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        wav
+    }
+
+    fn compare(original: &[u8], decrypted: &[u8]) -> Comparison {
+        compare_streams(&mut &original[..], &mut &decrypted[..]).unwrap()
+    }
+
+    #[test]
+    fn perturbed_samples_are_measured() {
+        let original = [1000, -2000, 3000, 400];
+        let same = compare(&wav(&original), &wav(&original));
+        assert!(same.audio && same.bit_exact());
+        assert_eq!((same.samples, same.max_error, same.snr), (4, 0.0, f64::INFINITY));
+
+        let perturbed = compare(&wav(&original), &wav(&[1000, -1990, 3000, 397]));
+        assert!(!perturbed.bit_exact());
+        assert_eq!((perturbed.samples, perturbed.mismatched), (4, 2));
+        assert_eq!(perturbed.max_error, 10.0);
+        assert_eq!(perturbed.mean_error, 13.0 / 4.0);
+        let snr = 10.0 * ((1000.0f64.powi(2) + 2000.0f64.powi(2) + 3000.0f64.powi(2) + 400.0f64.powi(2)) / 109.0).log10();
+        assert!((perturbed.snr - snr).abs() < 1e-9, "{} dB", perturbed.snr);
+
+        // A missing sample counts as silence.
+        let shorter = compare(&wav(&original), &wav(&original[..3]));
+        assert_eq!((shorter.samples, shorter.mismatched, shorter.max_error), (4, 1, 400.0));
+    }
+
+    #[test]
+    fn other_files_are_compared_byte_by_byte() {
+        let bytes = compare(b"hello", b"hellp");
+        assert!(!bytes.audio && !bytes.bit_exact());
+        assert_eq!((bytes.samples, bytes.mismatched, bytes.max_error), (5, 1, 1.0));
+    }
+}
@@ -0,0 +1,435 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    encryption::{key_fingerprint, Key, Mode, SolveReport},
+    error::{Error, Result},
+    stream::{decrypt_stream, encrypt_stream, encrypted_numbers},
+    text::Decryptor,
+    SolutionMethod,
+};
+
+/// The first bytes of every encrypted container.
+const CONTAINER_MAGIC: [u8; 4] = *b"CP1C";
+/// The current version of the container format.
+pub const CONTAINER_FORMAT_VERSION: u16 = 1;
+/// magic + version + payload type + mode + solver hints + original length + chunk size
+/// + key fingerprint + length of the payload metadata.
+const CONTAINER_HEADER_LEN: usize = 4 + 2 + 1 + 1 + 1 + 8 + 8 + 8 + 2;
+/// The length stored for payloads whose length was not known up front.
+const UNKNOWN_LENGTH: u64 = u64::MAX;
+
+/// What was encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadType {
+    /// The bytes of a text file.
+    Text,
+    /// The bytes of the samples of a `wav` file.
+    Audio,
+    /// The bytes of any other file.
+    Binary,
+    /// The samples of the selected channels of a `wav` file.
+    AudioChannels,
+    /// The bytes of the selected segments of the samples of a `wav` file.
+    AudioSegments,
+    /// The chunks of a `wav` file other than its format and samples.
+    AudioMetadata,
+}
+
+impl PayloadType {
+    fn to_byte(self) -> u8 {
+        match self {
+            PayloadType::Text => 0,
+            PayloadType::Audio => 1,
+            PayloadType::Binary => 2,
+            PayloadType::AudioChannels => 3,
+            PayloadType::AudioSegments => 4,
+            PayloadType::AudioMetadata => 5,
+        }
+    }
+
+    /// What the payload is, for error messages.
+    fn description(self) -> &'static str {
+        match self {
+            PayloadType::Text => "encrypted text",
+            PayloadType::Audio => "encrypted audio",
+            PayloadType::Binary => "an encrypted binary file",
+            PayloadType::AudioChannels => "audio with encrypted channels",
+            PayloadType::AudioSegments => "partially encrypted audio",
+            PayloadType::AudioMetadata => "encrypted audio metadata",
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(PayloadType::Text),
+            1 => Some(PayloadType::Audio),
+            2 => Some(PayloadType::Binary),
+            3 => Some(PayloadType::AudioChannels),
+            4 => Some(PayloadType::AudioSegments),
+            5 => Some(PayloadType::AudioMetadata),
+            _ => None,
+        }
+    }
+}
+
+/// What the encrypting side knows about how the ciphertext can be decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SolverHints {
+    /// Only the direct method can decrypt the payload (the modular cipher).
+    pub direct_only: bool,
+}
+
+impl SolverHints {
+    const DIRECT_ONLY: u8 = 1;
+
+    /// The hints for ciphertext encrypted in the given mode.
+    pub fn for_mode(mode: Mode) -> Self {
+        SolverHints {
+            direct_only: mode == Mode::Modular,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        if self.direct_only {
+            Self::DIRECT_ONLY
+        } else {
+            0
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        if byte & !Self::DIRECT_ONLY != 0 {
+            return None;
+        }
+        Some(SolverHints {
+            direct_only: byte & Self::DIRECT_ONLY != 0,
+        })
+    }
+}
+
+/// ## Container header
+/// Everything an encrypted file says about its contents besides the encrypted numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerHeader {
+    /// The version of the container format.
+    pub version: u16,
+    pub payload: PayloadType,
+    pub mode: Mode,
+    pub hints: SolverHints,
+    /// The number of bytes of the original payload. `None` if it was not known up front
+    /// (e.g. when reading the standard input), then the body is followed by a trailer with it.
+    pub length: Option<u64>,
+    /// The size of the key the payload was encrypted with.
+    pub chunk_size: u64,
+    /// See `key_fingerprint`.
+    pub fingerprint: u64,
+    /// Payload specific metadata, e.g. the format of the `wav` file.
+    pub metadata: Vec<u8>,
+}
+
+impl ContainerHeader {
+    /// The header of a payload of the given length (if known), encrypted with the key in the
+    /// given mode.
+    pub fn new(
+        payload: PayloadType,
+        length: Option<u64>,
+        metadata: Vec<u8>,
+        key: &Key,
+        mode: Mode,
+    ) -> Result<Self> {
+        if metadata.len() > u16::MAX as usize {
+            return Err(Error::UnsupportedFormat(format!(
+                "{} bytes of payload metadata is too much",
+                metadata.len()
+            )));
+        }
+        if length == Some(UNKNOWN_LENGTH) {
+            return Err(Error::UnsupportedFormat("the payload is too long".to_string()));
+        }
+
+        Ok(ContainerHeader {
+            version: CONTAINER_FORMAT_VERSION,
+            payload,
+            mode,
+            hints: SolverHints::for_mode(mode),
+            length,
+            chunk_size: key.n() as u64,
+            fingerprint: key_fingerprint(key),
+            metadata,
+        })
+    }
+
+    /// Makes sure the container holds the expected kind of payload.
+    pub fn check_payload(&self, payload: PayloadType) -> Result<()> {
+        if self.payload != payload {
+            return Err(Error::UnsupportedFormat(format!(
+                "the file does not contain {} but {}",
+                payload.description(),
+                self.payload.description()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Makes sure the container was encrypted with the given key, so that no time is spent
+    /// solving with the wrong one.
+    pub fn check_key(&self, key: &Key) -> Result<()> {
+        if self.chunk_size != key.n() as u64 || self.fingerprint != key_fingerprint(key) {
+            return Err(Error::KeyMismatch);
+        }
+        Ok(())
+    }
+
+    /// The number of bytes of the whole container (header, encrypted numbers and trailer),
+    /// if the length of the payload is known.
+    pub fn container_len(&self) -> Option<u64> {
+        let numbers = encrypted_numbers(self.length?, self.mode, self.chunk_size)?;
+        numbers
+            .checked_mul((self.mode.bits() / 8) as u64)?
+            .checked_add(self.header_len() as u64)
+    }
+
+    /// The number of bytes of the header, including the metadata.
+    pub fn header_len(&self) -> usize {
+        CONTAINER_HEADER_LEN + self.metadata.len()
+    }
+
+    /// Writes the header, then encrypts everything `reader` yields and writes the encrypted
+    /// numbers (and the trailer, if the length was not known). The reader has to yield exactly
+    /// `length` bytes, if it is known.
+    pub fn seal<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        key: &Key,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<()> {
+        self.check_key(key)?;
+        self.write_to(writer)?;
+
+        let read = encrypt_stream(reader, writer, key, self.mode)?;
+        match self.length {
+            Some(length) if length != read => {
+                return Err(Error::UnsupportedFormat(format!(
+                    "expected {length} bytes of input, but there were {read}"
+                )))
+            }
+            Some(_) => {}
+            None => writer.write_all(&read.to_be_bytes())?,
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the body can be decrypted with the key and the method, so that callers can
+    /// fail before writing anything.
+    pub fn check_decryptable(&self, key: &Key, method: &SolutionMethod) -> Result<()> {
+        self.check_key(key)?;
+        if self.hints.direct_only && !matches!(method, SolutionMethod::Direct) {
+            return Err(Error::InvalidArgument(
+                "this ciphertext can only be decrypted with the direct method".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the key and the method against the header, then decrypts the encrypted numbers
+    /// `reader` yields (everything after the header) and writes the payload to `writer`.
+    /// Returns the report of the iterative method (`None` for the direct one).
+    pub fn open<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        key: &Key,
+        method: &SolutionMethod,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<Option<SolveReport>> {
+        self.check_decryptable(key, method)?;
+
+        let mut decryptor = Decryptor::new(key, self.mode, method)?;
+        decrypt_stream(reader, writer, &mut decryptor, self.length)?;
+        Ok(decryptor.into_report())
+    }
+
+    /// Serializes the header:
+    /// - magic (`CP1C`)
+    /// - version (`u16`)
+    /// - payload type (`u8`)
+    /// - mode, the number of bits of an encrypted number (`u8`)
+    /// - solver hints (`u8` flags)
+    /// - length of the original payload in bytes (`u64`, all ones if it is in the trailer)
+    /// - size of the key (`u64`)
+    /// - key fingerprint (`u64`)
+    /// - length of the payload metadata (`u16`), followed by the metadata
+    ///
+    /// It is followed by the encrypted numbers, `mode.bits() / 8` bytes each, and if the length
+    /// was not known, the trailer with the length (`u64`). All numbers are big-endian.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> Result<()> {
+        let mut data = Vec::with_capacity(CONTAINER_HEADER_LEN + self.metadata.len());
+        data.extend_from_slice(&CONTAINER_MAGIC);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.push(self.payload.to_byte());
+        data.push(self.mode.bits() as u8);
+        data.push(self.hints.to_byte());
+        data.extend_from_slice(&self.length.unwrap_or(UNKNOWN_LENGTH).to_be_bytes());
+        data.extend_from_slice(&self.chunk_size.to_be_bytes());
+        data.extend_from_slice(&self.fingerprint.to_be_bytes());
+        data.extend_from_slice(&(self.metadata.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.metadata);
+        writer.write_all(&data)?;
+
+        Ok(())
+    }
+
+    /// Parses a header written by `write_to`, leaving `reader` at the start of the encrypted
+    /// numbers.
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
+        let missing = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                Error::MalformedCiphertext("missing container header".to_string())
+            }
+            _ => Error::from(e),
+        };
+        let mut buf = [0; CONTAINER_HEADER_LEN];
+        reader.read_exact(&mut buf).map_err(missing)?;
+        if !is_container(&buf) {
+            return Err(Error::MalformedCiphertext("missing container header".to_string()));
+        }
+
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        if version != CONTAINER_FORMAT_VERSION {
+            return Err(Error::MalformedCiphertext(format!(
+                "unsupported container format version {version}"
+            )));
+        }
+        let payload = PayloadType::from_byte(buf[6])
+            .ok_or_else(|| Error::MalformedCiphertext(format!("unknown payload type {}", buf[6])))?;
+        let mode = Mode::from_bits(buf[7] as usize)
+            .ok_or_else(|| Error::MalformedCiphertext(format!("invalid mode `{}` in header", buf[7])))?;
+        let hints = SolverHints::from_byte(buf[8])
+            .ok_or_else(|| Error::MalformedCiphertext(format!("unknown solver hints {:#04x}", buf[8])))?;
+        let length = u64::from_be_bytes(buf[9..17].try_into().unwrap());
+        let chunk_size = u64::from_be_bytes(buf[17..25].try_into().unwrap());
+        let fingerprint = u64::from_be_bytes(buf[25..33].try_into().unwrap());
+        let metadata_len = u16::from_be_bytes([buf[33], buf[34]]) as usize;
+
+        if chunk_size == 0 {
+            return Err(Error::MalformedCiphertext("the chunk size is zero".to_string()));
+        }
+        let mut metadata = vec![0; metadata_len];
+        reader.read_exact(&mut metadata).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                Error::MalformedCiphertext("the payload metadata is truncated".to_string())
+            }
+            _ => Error::from(e),
+        })?;
+
+        Ok(ContainerHeader {
+            version,
+            payload,
+            mode,
+            hints,
+            length: (length != UNKNOWN_LENGTH).then_some(length),
+            chunk_size,
+            fingerprint,
+            metadata,
+        })
+    }
+}
+
+/// Whether the buffer starts like a container.
+pub fn is_container(buf: &[u8]) -> bool {
+    buf.starts_with(&CONTAINER_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{gen_key, gen_modular_key, IterativeOptions, Precision};
+
+    fn header() -> (ContainerHeader, Vec<u8>) {
+        let key = Key::Float(gen_key(4, false).unwrap());
+        let header = ContainerHeader::new(
+            PayloadType::Binary,
+            Some(100),
+            b"csv".to_vec(),
+            &key,
+            Mode::Float(Precision::Single),
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).unwrap();
+        (header, buf)
+    }
+
+    fn read(buf: &[u8]) -> Result<ContainerHeader> {
+        ContainerHeader::read_from(&mut &buf[..])
+    }
+
+    #[test]
+    fn headers_roundtrip() {
+        let (header, buf) = header();
+        assert_eq!(buf.len(), header.header_len());
+        assert_eq!(read(&buf).unwrap(), header);
+
+        let unknown = ContainerHeader { length: None, ..header };
+        let mut buf = Vec::new();
+        unknown.write_to(&mut buf).unwrap();
+        assert_eq!(read(&buf).unwrap(), unknown);
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let (_, buf) = header();
+        for len in 0..buf.len() {
+            assert!(
+                matches!(read(&buf[..len]), Err(Error::MalformedCiphertext(_))),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_headers_are_rejected() {
+        let (_, buf) = header();
+        let corrupt = |i: usize, byte: u8| {
+            let mut buf = buf.clone();
+            buf[i] = byte;
+            read(&buf)
+        };
+
+        // magic, version, payload type, mode and solver hints
+        for (i, byte) in [(0, b'X'), (5, 2), (6, 0xff), (7, 16), (8, 0x80)] {
+            assert!(matches!(corrupt(i, byte), Err(Error::MalformedCiphertext(_))), "byte {i}");
+        }
+
+        let mut zero_chunk_size = buf.clone();
+        zero_chunk_size[17..25].fill(0);
+        assert!(matches!(read(&zero_chunk_size), Err(Error::MalformedCiphertext(e)) if e.contains("chunk size")));
+
+        let mut long_metadata = buf.clone();
+        long_metadata[34] += 1;
+        assert!(matches!(read(&long_metadata), Err(Error::MalformedCiphertext(e)) if e.contains("metadata")));
+    }
+
+    #[test]
+    fn containers_only_open_with_their_key() {
+        let key = Key::Modular(gen_modular_key(4).unwrap());
+        let header = ContainerHeader::new(PayloadType::Text, None, Vec::new(), &key, Mode::Modular).unwrap();
+        let mut container = Vec::new();
+        header.seal(&key, &mut &b"attack at dawn"[..], &mut container).unwrap();
+
+        let mut reader = container.as_slice();
+        let read = ContainerHeader::read_from(&mut reader).unwrap();
+        assert_eq!(read, header);
+
+        let other = Key::Modular(gen_modular_key(4).unwrap());
+        let mismatch = read.open(&other, &SolutionMethod::Direct, &mut reader, &mut Vec::new());
+        assert!(matches!(mismatch, Err(Error::KeyMismatch)));
+        let method = SolutionMethod::Iterative(IterativeOptions::default());
+        let iterative = read.open(&key, &method, &mut reader, &mut Vec::new());
+        assert!(matches!(iterative, Err(Error::InvalidArgument(_))));
+
+        let mut text = Vec::new();
+        read.open(&key, &SolutionMethod::Direct, &mut reader, &mut text).unwrap();
+        assert_eq!(text, b"attack at dawn");
+    }
+}
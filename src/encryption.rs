@@ -1,151 +1,1315 @@
-use std::{path::Path, fs::File, io::{self, Read, Write}};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use pbkdf2::pbkdf2_hmac;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 
-use crate::matrix::{FloatMatrix, Matrix};
+use crate::{
+    error::{Error, Result},
+    matrix::{
+        BiCgStab, ConjugateGradient, Convergence, Field, FloatElement, FloatMatrix, GaussSeidel, Gmres, Jacobi,
+        JacobiPreconditioner, LuPreconditioner, Matrix, MatrixElement, ModularMatrix, Preconditioner, RandomRange, Solver,
+        Sor, Ssor, Tolerance, Z257,
+    },
+};
 
+#[derive(Debug, Clone)]
 pub enum SolutionMethod {
     Direct,
-    Iterative(usize)
+    Iterative(IterativeOptions)
 }
 
+/// The iterative methods the systems can be solved with, see the `Solver`s in `matrix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IterativeMethod {
+    Jacobi,
+    GaussSeidel,
+    #[default]
+    Sor,
+    Ssor,
+    ConjugateGradient,
+    /// Restarted after the given number of iterations.
+    Gmres(usize),
+    BiCgStab,
+}
+
+impl IterativeMethod {
+    /// Whether the method takes a relaxation parameter.
+    pub fn is_relaxed(self) -> bool {
+        matches!(self, IterativeMethod::Sor | IterativeMethod::Ssor)
+    }
+
+    /// Whether the method is a Krylov one, which can be preconditioned.
+    pub fn is_krylov(self) -> bool {
+        matches!(
+            self,
+            IterativeMethod::ConjugateGradient | IterativeMethod::Gmres(_) | IterativeMethod::BiCgStab
+        )
+    }
+}
+
+impl Display for IterativeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IterativeMethod::Jacobi => write!(f, "Jacobi"),
+            IterativeMethod::GaussSeidel => write!(f, "Gauss-Seidel"),
+            IterativeMethod::Sor => write!(f, "SOR"),
+            IterativeMethod::Ssor => write!(f, "SSOR"),
+            IterativeMethod::ConjugateGradient => write!(f, "CG (normal equations)"),
+            IterativeMethod::Gmres(restart) => write!(f, "GMRES({restart})"),
+            IterativeMethod::BiCgStab => write!(f, "BiCGSTAB"),
+        }
+    }
+}
+
+/// The preconditioners of the Krylov methods, see the `Preconditioner`s in `matrix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preconditioning {
+    /// The diagonal of `K`.
+    Jacobi,
+    /// The incomplete LU factorization of `K`.
+    Ilu0,
+    /// SSOR with the relaxation parameter of the options.
+    Ssor,
+    /// The `tril` and `triu` of the key, whose product is `K`.
+    Exact,
+}
+
+impl Display for Preconditioning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Preconditioning::Jacobi => write!(f, "Jacobi"),
+            Preconditioning::Ilu0 => write!(f, "ILU(0)"),
+            Preconditioning::Ssor => write!(f, "SSOR"),
+            Preconditioning::Exact => write!(f, "exact LU"),
+        }
+    }
+}
+
+/// The number of columns which did not converge a `SolveReport` lists.
+const UNCONVERGED_LISTED: usize = 16;
+
+/// ## Relaxation parameter
+/// The `omega` of SOR: a fixed one, or `auto`, the optimal one for the system matrix,
+/// `2 / (1 + sqrt(1 - rho^2))` where `rho` is the spectral radius of its Jacobi iteration matrix.
+/// If the Jacobi iteration does not converge (`rho >= 1`), `auto` falls back to Gauss-Seidel
+/// (`omega = 1`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Omega {
+    Auto,
+    Fixed(f64),
+}
+
+impl Default for Omega {
+    fn default() -> Self {
+        Omega::Fixed(1.3)
+    }
+}
+
+impl Omega {
+    /// The relaxation parameter for the system matrix.
+    pub fn value<T: FloatElement>(self, k: &Matrix<T>) -> Result<f64> {
+        match self {
+            Omega::Fixed(omega) => Ok(omega),
+            Omega::Auto => {
+                let rho = k.jacobi_spectral_radius()?;
+                if rho < 1.0 {
+                    Ok(2.0 / (1.0 + (1.0 - rho * rho).sqrt()))
+                } else {
+                    Ok(1.0)
+                }
+            }
+        }
+    }
+}
+
+impl Display for Omega {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Omega::Auto => write!(f, "auto"),
+            Omega::Fixed(omega) => write!(f, "{omega}"),
+        }
+    }
+}
+
+impl FromStr for Omega {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(Omega::Auto);
+        }
+        s.parse::<f64>()
+            .ok()
+            .filter(|omega| *omega > 0.0 && *omega < 2.0)
+            .map(Omega::Fixed)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("invalid omega `{s}` (expected `auto` or a number in (0, 2))"))
+            })
+    }
+}
+
+/// ## Iterative options
+/// The method, its preconditioner and relaxation parameter (if it takes them), and when the
+/// iterations stop.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct IterativeOptions {
+    pub method: IterativeMethod,
+    pub preconditioner: Option<Preconditioning>,
+    pub omega: Omega,
+    pub tolerance: Tolerance,
+}
+
+impl IterativeOptions {
+    /// Returns an error if there is a preconditioner but the method is not a Krylov one, or if
+    /// the fixed relaxation parameter is not in `(0, 2)`, where SOR can not converge.
+    pub fn new(
+        method: IterativeMethod,
+        preconditioner: Option<Preconditioning>,
+        omega: Omega,
+        tolerance: Tolerance,
+    ) -> Result<Self> {
+        if let Omega::Fixed(omega) = omega {
+            if !(omega > 0.0 && omega < 2.0) {
+                return Err(Error::InvalidArgument(format!("omega must be in (0, 2), not {omega}")));
+            }
+        }
+        if preconditioner.is_some() && !method.is_krylov() {
+            return Err(Error::InvalidArgument(format!(
+                "{method} can not be preconditioned (only CG, GMRES and BiCGSTAB can)"
+            )));
+        }
+        Ok(IterativeOptions { method, preconditioner, omega, tolerance })
+    }
+}
+
+/// ## Iterative system
+/// Everything solving `Kx = b` iteratively takes besides `b`: the system matrix
+/// `K = tril(key) triu(key)`, the solver with its preconditioner, and its relaxation parameter.
+/// Building it is the expensive part (the ILU(0), SSOR and exact preconditioners factor `K`,
+/// `Omega::Auto` runs a power iteration), so it is built once per key and then solves any
+/// number of blocks of columns.
+pub struct IterativeSystem<T: FloatElement> {
+    k: Matrix<T>,
+    solver: Box<dyn Solver<T>>,
+    omega: Option<f64>,
+    tolerance: Tolerance,
+}
+
+impl<T: FloatElement> IterativeSystem<T> {
+    pub fn new(key: &Matrix<T>, options: &IterativeOptions) -> Result<Self> {
+        check_key_shape(key)?;
+        let k = key.tril().dot(&key.triu())?;
+
+        let relaxed = options.method.is_relaxed() || options.preconditioner == Some(Preconditioning::Ssor);
+        let omega = if relaxed { Some(options.omega.value(&k)?) } else { None };
+        let preconditioner: Option<Box<dyn Preconditioner>> = match options.preconditioner {
+            None => None,
+            Some(Preconditioning::Jacobi) => Some(Box::new(JacobiPreconditioner::new(&k)?)),
+            Some(Preconditioning::Ilu0) => Some(Box::new(LuPreconditioner::ilu0(&k)?)),
+            Some(Preconditioning::Ssor) => Some(Box::new(LuPreconditioner::ssor(&k, omega.unwrap())?)),
+            Some(Preconditioning::Exact) => Some(Box::new(LuPreconditioner::from_factors(&key.tril(), &key.triu())?)),
+        };
+        let solver: Box<dyn Solver<T>> = match options.method {
+            IterativeMethod::Jacobi => Box::new(Jacobi),
+            IterativeMethod::GaussSeidel => Box::new(GaussSeidel),
+            IterativeMethod::Sor => Box::new(Sor { omega: omega.unwrap() }),
+            IterativeMethod::Ssor => Box::new(Ssor { omega: omega.unwrap() }),
+            IterativeMethod::ConjugateGradient => Box::new(ConjugateGradient { preconditioner }),
+            IterativeMethod::Gmres(restart) => Box::new(Gmres { restart, preconditioner }),
+            IterativeMethod::BiCgStab => Box::new(BiCgStab { preconditioner }),
+        };
+
+        Ok(IterativeSystem { k, solver, omega, tolerance: options.tolerance })
+    }
+
+    /// The size of the system.
+    pub fn n(&self) -> usize {
+        self.k.n
+    }
+
+    /// The relaxation parameter, if the method (or its preconditioner) takes one.
+    pub fn omega(&self) -> Option<f64> {
+        self.omega
+    }
+
+    /// Solves for every column of `data` until its residual is within the tolerance, and returns
+    /// the solutions together with how every column converged.
+    pub fn solve(&self, data: Matrix<T>) -> Result<(Matrix<T>, Vec<Convergence>)> {
+        check_decryption_shapes(&self.k, &data)?;
+        // Every row of the transposed matrix is a column of `data`, stored contiguously.
+        let mut data = data.transpose();
+
+        // Decrypt the columns in place.
+        let columns = data.data
+            .par_chunks_mut(self.k.n)
+            .map(|column| -> Result<Convergence> {
+                let (x, convergence) = self.solver.solve(&self.k, column, &self.tolerance)?;
+                column.copy_from_slice(&x);
+                Ok(convergence)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((data.transpose(), columns))
+    }
+}
+
+/// ## Solve report
+/// How the iterative solutions of all the columns (systems) of a decryption went.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SolveReport {
+    /// The name of the method.
+    pub method: String,
+    /// The number of columns solved.
+    pub columns: u64,
+    /// The number of columns whose residual got within the tolerance.
+    pub converged: u64,
+    /// The number of iterations of all the columns.
+    pub iterations: u64,
+    /// The most iterations a column took.
+    pub max_iterations: usize,
+    /// The largest final residual of a column.
+    pub max_residual: f64,
+    /// The first columns (numbered from 0) which did not converge.
+    pub unconverged: Vec<u64>,
+    /// The relaxation parameters used (more than one if the columns were solved with different
+    /// slices of the key).
+    pub omega: Vec<f64>,
+}
+
+impl SolveReport {
+    /// An empty report of the method of the options.
+    pub fn new(options: &IterativeOptions) -> Self {
+        let method = match options.preconditioner {
+            Some(preconditioner) => format!("{}, {preconditioner} preconditioned", options.method),
+            None => options.method.to_string(),
+        };
+        SolveReport { method, ..SolveReport::default() }
+    }
+
+    /// Adds the columns, in order, solved with the given relaxation parameter.
+    pub fn record(&mut self, omega: Option<f64>, columns: &[Convergence]) {
+        if let Some(omega) = omega.filter(|omega| !self.omega.contains(omega)) {
+            self.omega.push(omega);
+        }
+        for (i, column) in columns.iter().enumerate() {
+            if column.converged {
+                self.converged += 1;
+            } else if self.unconverged.len() < UNCONVERGED_LISTED {
+                self.unconverged.push(self.columns + i as u64);
+            }
+            self.iterations += column.iterations as u64;
+            self.max_iterations = self.max_iterations.max(column.iterations);
+            // `NaN` if the column diverged, which sticks.
+            if column.residual.is_nan() || column.residual > self.max_residual {
+                self.max_residual = column.residual;
+            }
+        }
+        self.columns += columns.len() as u64;
+    }
+
+    /// Adds the columns of another report (of the same method) after the ones of this one.
+    pub fn merge(&mut self, other: &SolveReport) {
+        for omega in &other.omega {
+            if !self.omega.contains(omega) {
+                self.omega.push(*omega);
+            }
+        }
+        for &column in &other.unconverged {
+            if self.unconverged.len() < UNCONVERGED_LISTED {
+                self.unconverged.push(self.columns + column);
+            }
+        }
+        self.converged += other.converged;
+        self.iterations += other.iterations;
+        self.max_iterations = self.max_iterations.max(other.max_iterations);
+        if other.max_residual.is_nan() || other.max_residual > self.max_residual {
+            self.max_residual = other.max_residual;
+        }
+        self.columns += other.columns;
+    }
+
+    /// Whether every column converged.
+    pub fn converged(&self) -> bool {
+        self.converged == self.columns
+    }
+
+    /// The mean number of iterations of a column.
+    pub fn mean_iterations(&self) -> f64 {
+        if self.columns == 0 {
+            return 0.0;
+        }
+        self.iterations as f64 / self.columns as f64
+    }
+
+    /// The report as a JSON object (a residual which is not a number is `null`).
+    pub fn to_json(&self) -> String {
+        let number = |x: f64| if x.is_finite() { x.to_string() } else { "null".to_string() };
+        let unconverged = self.unconverged.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let omega = self.omega.iter().map(|&omega| number(omega)).collect::<Vec<_>>();
+        format!(
+            "{{\"method\":\"{}\",\"omega\":[{}],\"columns\":{},\"converged\":{},\"iterations\":{},\"max_iterations\":{},\"mean_iterations\":{},\"max_residual\":{},\"unconverged\":[{}]}}",
+            self.method,
+            omega.join(","),
+            self.columns,
+            self.converged,
+            self.iterations,
+            self.max_iterations,
+            number(self.mean_iterations()),
+            number(self.max_residual),
+            unconverged.join(",")
+        )
+    }
+}
+
+impl Display for SolveReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let omega = self.omega.iter().map(|omega| format!("{omega:.4}")).collect::<Vec<_>>();
+        writeln!(f, "method: {}", self.method)?;
+        if !omega.is_empty() {
+            writeln!(f, "omega: {}", omega.join(", "))?;
+        }
+        writeln!(f, "columns: {}", self.columns)?;
+        writeln!(f, "converged: {}", self.converged)?;
+        writeln!(f, "iterations: {:.1} on average, at most {}", self.mean_iterations(), self.max_iterations)?;
+        write!(f, "max residual: {:e}", self.max_residual)?;
+        if !self.unconverged.is_empty() {
+            let unconverged = self.unconverged.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+            let more = if self.columns - self.converged > self.unconverged.len() as u64 { ", ..." } else { "" };
+            write!(f, "\nnot converged: columns {}{more}", unconverged.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The width of the floating point numbers the data is encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    /// `f32`
+    #[default]
+    Single,
+    /// `f64`
+    Double,
+}
+
+impl Precision {
+    /// The number of bits of a single encrypted number.
+    pub fn bits(self) -> usize {
+        match self {
+            Precision::Single => f32::BIT_WIDTH,
+            Precision::Double => f64::BIT_WIDTH,
+        }
+    }
+
+    /// Returns the precision with the given number of bits.
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            32 => Some(Precision::Single),
+            64 => Some(Precision::Double),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "f{}", self.bits())
+    }
+}
+
+impl FromStr for Precision {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "f32" => Ok(Precision::Single),
+            "f64" => Ok(Precision::Double),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown precision `{s}` (expected `f32` or `f64`)"
+            ))),
+        }
+    }
+}
+
+/// How the data is turned into matrix elements and encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Nibbles multiplied by a real matrix, with floats of the given precision.
+    Float(Precision),
+    /// Bytes multiplied by a matrix over `Z_257`, exact and size-preserving.
+    Modular,
+}
+
+impl Mode {
+    /// The number of bits of a single encrypted number.
+    pub fn bits(self) -> usize {
+        match self {
+            Mode::Float(precision) => precision.bits(),
+            Mode::Modular => 8,
+        }
+    }
+
+    /// Returns the mode whose encrypted numbers have the given number of bits.
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            8 => Some(Mode::Modular),
+            _ => Precision::from_bits(bits).map(Mode::Float),
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Float(Precision::default())
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Float(precision) => write!(f, "{precision}"),
+            Mode::Modular => write!(f, "z{}", Z257::MODULUS),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == Mode::Modular.to_string() {
+            Ok(Mode::Modular)
+        } else {
+            s.parse().map(Mode::Float)
+        }
+    }
+}
 
 /// Generates `chunk_size`x`chunk_size` key matrix. This key matrix later be divided into `L` and `U` (`key = L + U + I`)
 /// and `K = (L + I)(U + I)` will give the encryption matrix with `det(K) = 1`.
-pub fn gen_key(chunk_size: usize, integer: bool) -> FloatMatrix {
+pub fn gen_key(chunk_size: usize, integer: bool) -> Result<FloatMatrix> {
+    gen_key_with_rng(chunk_size, integer, &mut rand::thread_rng())
+}
+
+/// Same as `gen_key`, but the random entries are drawn from `rng`.
+pub fn gen_key_with_rng<R: Rng + ?Sized>(chunk_size: usize, integer: bool, rng: &mut R) -> Result<FloatMatrix> {
+    if chunk_size == 0 {
+        return Err(Error::InvalidArgument("the key size must be at least 1".to_string()));
+    }
+
     // Generate `chunk_size`x`chunk_size` matrix, every element of which is between 0 and 1/`chunk_size`.
-    let key = FloatMatrix::random(chunk_size, chunk_size, &0.0, &(1.0 / chunk_size as f32));
+    let key = FloatMatrix::random_with_rng(chunk_size, chunk_size, &0.0, &(1.0 / chunk_size as f32), rng);
     
     // Make the diagonal of the key all ones.
-    let key = key.clone() - key.hadamard(FloatMatrix::identity(chunk_size))
-        + FloatMatrix::identity(chunk_size);
+    let key = ((key.clone() - key.hadamard(FloatMatrix::identity(chunk_size))?)?
+        + FloatMatrix::identity(chunk_size))?;
     
     // Round the elements of the key so that all entries are in 2-digit arithmetic.
     let key = key.round(chunk_size.ilog10() as usize + 1);
     
     // If requested so, convert the matrix to only have whole numbers.
     if integer {
-        (key - Matrix::identity(chunk_size)).scale(chunk_size as f32 * 10.0).round(0) + Matrix::identity(chunk_size).scale(chunk_size as f32)
+        (key - Matrix::identity(chunk_size))?.scale(chunk_size as f32 * 10.0).round(0) + Matrix::identity(chunk_size).scale(chunk_size as f32)
     } else {
-        key
+        Ok(key)
+    }
+}
+
+/// The largest key the modular cipher accepts. A column which leaves the bytes is walked again
+/// (see `is_byte`), which happens with probability `1 - (256/257)^n`, so a column takes
+/// `e^(n/257)` multiplications on average: about 7 at this size, but about 3000 at 2048.
+pub const MAX_MODULAR_KEY_SIZE: usize = 512;
+
+/// Generates `chunk_size`x`chunk_size` key matrix for the modular cipher. Just like with `gen_key`,
+/// `K = tril(key) * triu(key)`, but the entries are elements of `Z_257` and the diagonal is random
+/// and nonzero, so `det(K) = prod(diag(key))^2` is never zero and `K` is invertible mod 257.
+pub fn gen_modular_key(chunk_size: usize) -> Result<ModularMatrix> {
+    gen_modular_key_with_rng(chunk_size, &mut rand::thread_rng())
+}
+
+/// Same as `gen_modular_key`, but the random entries are drawn from `rng`.
+pub fn gen_modular_key_with_rng<R: Rng + ?Sized>(chunk_size: usize, rng: &mut R) -> Result<ModularMatrix> {
+    if chunk_size == 0 {
+        return Err(Error::InvalidArgument("the key size must be at least 1".to_string()));
+    }
+    if chunk_size > MAX_MODULAR_KEY_SIZE {
+        return Err(Error::InvalidArgument(format!(
+            "a modular key can be at most {MAX_MODULAR_KEY_SIZE}x{MAX_MODULAR_KEY_SIZE}"
+        )));
+    }
+
+    let mut key = ModularMatrix::random_with_rng(chunk_size, chunk_size, &Z257::ZERO, &Z257::new(256), rng);
+    for i in 0..chunk_size {
+        key[i][i] = Z257::rand_range(rng, &Z257::ONE, &Z257::new(256));
+    }
+
+    Ok(key)
+}
+
+/// The number of PBKDF2 rounds used by `derive_key_rng`.
+pub const KEY_DERIVATION_ROUNDS: u32 = 100_000;
+
+/// Derives a seeded random number generator from a passphrase and a seed, so that two parties
+/// who know both can generate the same key independently (with `gen_key_with_rng` or
+/// `gen_modular_key_with_rng` and the same size and kind).
+///
+/// The 256-bit ChaCha20 seed is PBKDF2-HMAC-SHA256 of the passphrase, salted with the seed.
+pub fn derive_key_rng(passphrase: &str, seed: u64) -> ChaCha20Rng {
+    let salt = [b"cp1-key".as_slice(), &seed.to_be_bytes()].concat();
+
+    let mut rng_seed = [0; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, KEY_DERIVATION_ROUNDS, &mut rng_seed);
+
+    ChaCha20Rng::from_seed(rng_seed)
+}
+
+/// ## Key
+/// A key matrix, typed by what it was generated for (see `KeyKind`), so a modular key can not
+/// end up in the float cipher or the other way around.
+#[derive(Clone, PartialEq)]
+pub enum Key {
+    /// A key of `gen_key` with real entries (`KeyKind::Float`).
+    Float(FloatMatrix),
+    /// A key of `gen_key` with whole number entries (`KeyKind::Integer`), for the float cipher.
+    Integer(FloatMatrix),
+    /// A key of `gen_modular_key` (`KeyKind::Modular`).
+    Modular(ModularMatrix),
+}
+
+impl Key {
+    /// The size of the key matrix.
+    pub fn n(&self) -> usize {
+        match self {
+            Key::Float(key) | Key::Integer(key) => key.n,
+            Key::Modular(key) => key.n,
+        }
+    }
+
+    /// What the key was generated for.
+    pub fn kind(&self) -> KeyKind {
+        match self {
+            Key::Float(_) => KeyKind::Float,
+            Key::Integer(_) => KeyKind::Integer,
+            Key::Modular(_) => KeyKind::Modular,
+        }
+    }
+
+    /// Checks that the key is for the cipher of the mode.
+    pub fn check_mode(&self, mode: Mode) -> Result<()> {
+        match mode {
+            Mode::Float(_) => self.float().map(|_| ()),
+            Mode::Modular => self.modular().map(|_| ()),
+        }
+    }
+
+    /// The matrix of a key for the float cipher.
+    pub fn float(&self) -> Result<&FloatMatrix> {
+        match self {
+            Key::Float(key) | Key::Integer(key) => Ok(key),
+            Key::Modular(_) => Err(Error::MalformedKey(
+                "the key is for the modular cipher, not the float one".to_string(),
+            )),
+        }
+    }
+
+    /// The matrix of a key for the modular cipher.
+    pub fn modular(&self) -> Result<&ModularMatrix> {
+        match self {
+            Key::Modular(key) => Ok(key),
+            Key::Float(_) | Key::Integer(_) => Err(Error::MalformedKey(
+                "the key is for the float cipher, not the modular one".to_string(),
+            )),
+        }
+    }
+
+    /// The `len`x`len` block on the diagonal whose first row (and column) is `start`.
+    pub fn diagonal_block(&self, start: usize, len: usize) -> Key {
+        match self {
+            Key::Float(key) => Key::Float(key.diagonal_block(start, len)),
+            Key::Integer(key) => Key::Integer(key.diagonal_block(start, len)),
+            Key::Modular(key) => Key::Modular(key.diagonal_block(start, len)),
+        }
+    }
+}
+
+impl From<FloatMatrix> for Key {
+    fn from(key: FloatMatrix) -> Self {
+        Key::Float(key)
+    }
+}
+
+impl From<ModularMatrix> for Key {
+    fn from(key: ModularMatrix) -> Self {
+        Key::Modular(key)
+    }
+}
+
+/// The first bytes of every key file (except for the legacy ones).
+const KEY_MAGIC: [u8; 4] = *b"CP1K";
+/// The current version of the key file format.
+pub const KEY_FORMAT_VERSION: u16 = 1;
+/// magic + version + element type + key kind + creation time + size.
+const KEY_HEADER_LEN: usize = 4 + 2 + 1 + 1 + 8 + 8;
+/// The CRC-32 of everything before it.
+const KEY_CHECKSUM_LEN: usize = 4;
+
+/// What the key was generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    /// `gen_key` with real entries.
+    Float,
+    /// `gen_key` with whole number entries.
+    Integer,
+    /// `gen_modular_key`.
+    Modular,
+}
+
+/// How the entries of the key are stored in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyElement {
+    /// Big-endian `f32`.
+    F32,
+    /// Big-endian `u32` (the representatives of the modular keys).
+    U32,
+}
+
+/// ## Key metadata
+/// Everything a key file says about the key besides the entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// The version of the file format. `0` for the legacy format.
+    pub version: u16,
+    pub element: KeyElement,
+    pub kind: KeyKind,
+    /// Seconds since the unix epoch. `None` for the legacy format.
+    pub created: Option<u64>,
+}
+
+impl KeyKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyKind::Float => 0,
+            KeyKind::Integer => 1,
+            KeyKind::Modular => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(KeyKind::Float),
+            1 => Some(KeyKind::Integer),
+            2 => Some(KeyKind::Modular),
+            _ => None,
+        }
+    }
+}
+
+impl KeyElement {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyElement::F32 => 0,
+            KeyElement::U32 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(KeyElement::F32),
+            1 => Some(KeyElement::U32),
+            _ => None,
+        }
     }
 }
 
 /// Loads the key matrix from the given file.
-pub fn load_key(file_path: &str) -> io::Result<(usize, FloatMatrix)> {
+pub fn load_key(file_path: &str) -> Result<(usize, Key)> {
+    let (_, key) = load_key_with_metadata(file_path)?;
+    Ok((key.n(), key))
+}
+
+/// Loads the key matrix and its metadata from the given file. Both the current and the
+/// legacy format (a big-endian `usize` size followed by the `f32` entries) can be read;
+/// legacy keys are always float keys.
+pub fn load_key_with_metadata(file_path: &str) -> Result<(KeyMetadata, Key)> {
     let mut file = File::open(Path::new(file_path))?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
 
-    // Read the first usize - the size of the key.
-    let mut n_bytes = [0; 8];
-    for i in 0..8 {
-        n_bytes[i] = buf[i];
+    if buf.starts_with(&KEY_MAGIC) {
+        parse_key(&buf)
+    } else {
+        parse_legacy_key(&buf)
     }
-    let n = usize::from_be_bytes(n_bytes);
+}
+
+/// Returns the number of bytes `n`x`n` entries of `size` bytes take, if it does not overflow.
+fn key_body_len(n: usize, size: usize) -> Option<usize> {
+    n.checked_mul(n).and_then(|n2| n2.checked_mul(size))
+}
 
-    let mut key = FloatMatrix::zero(n, n);
+/// Parses a key file of the current format:
+/// - magic (`CP1K`)
+/// - version (`u16`)
+/// - element type (`u8`)
+/// - key kind (`u8`)
+/// - creation time (`u64`, seconds since the unix epoch)
+/// - size of the key (`u64`)
+/// - the entries, row by row
+/// - CRC-32 of all of the above (`u32`)
+///
+/// All numbers are big-endian.
+fn parse_key(buf: &[u8]) -> Result<(KeyMetadata, Key)> {
+    if buf.len() < KEY_HEADER_LEN + KEY_CHECKSUM_LEN {
+        return Err(Error::MalformedKey("the file is too short to contain a header".to_string()));
+    }
 
-    for i in 0..n {
-        for j in 0..n {
-            let ind = (i * n + j + 2) * 4;
+    let version = u16::from_be_bytes([buf[4], buf[5]]);
+    if version != KEY_FORMAT_VERSION {
+        return Err(Error::MalformedKey(format!("unsupported key format version {version}")));
+    }
+    let element = KeyElement::from_byte(buf[6])
+        .ok_or_else(|| Error::MalformedKey(format!("unknown element type {}", buf[6])))?;
+    let kind = KeyKind::from_byte(buf[7])
+        .ok_or_else(|| Error::MalformedKey(format!("unknown key kind {}", buf[7])))?;
+    let created = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    let n = u64::from_be_bytes(buf[16..24].try_into().unwrap()) as usize;
 
-            let mut bytes = [0; 4];
-            for k in 0..4 {
-                bytes[k] = buf[k + ind];
-            }
-            key.data[i][j] = f32::from_be_bytes(bytes);
+    if n == 0 {
+        return Err(Error::MalformedKey("the key size is zero".to_string()));
+    }
+    let expected_len = key_body_len(n, 4)
+        .and_then(|len| len.checked_add(KEY_HEADER_LEN + KEY_CHECKSUM_LEN));
+    if expected_len != Some(buf.len()) {
+        return Err(Error::MalformedKey(format!(
+            "the file is truncated or too long for a {n}x{n} key"
+        )));
+    }
+
+    let (contents, checksum) = buf.split_at(buf.len() - KEY_CHECKSUM_LEN);
+    if crc32fast::hash(contents) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        return Err(Error::MalformedKey("checksum mismatch, the file is corrupted".to_string()));
+    }
+
+    let data = contents[KEY_HEADER_LEN..]
+        .chunks_exact(4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let key = match (kind, element) {
+        (KeyKind::Modular, KeyElement::U32) => Key::Modular(modular_key(n, data)?),
+        (KeyKind::Modular, KeyElement::F32) => {
+            return Err(Error::MalformedKey("a modular key must have integer entries".to_string()));
         }
+        (_, KeyElement::F32) => float_key(kind, FloatMatrix::from_vec(n, n, data.into_iter().map(f32::from_bits).collect())?),
+        (_, KeyElement::U32) => float_key(kind, FloatMatrix::from_vec(n, n, data.into_iter().map(|x| x as f32).collect())?),
+    };
+
+    let metadata = KeyMetadata {
+        version,
+        element,
+        kind,
+        created: Some(created),
+    };
+
+    Ok((metadata, key))
+}
+
+/// Builds the key of the modular cipher from the entries of a key file. Every entry must be a
+/// representative in `[0, 257)` and the diagonal must be nonzero.
+fn modular_key(n: usize, entries: Vec<u32>) -> Result<ModularMatrix> {
+    if n > MAX_MODULAR_KEY_SIZE {
+        return Err(Error::MalformedKey(format!(
+            "a modular key can be at most {MAX_MODULAR_KEY_SIZE}x{MAX_MODULAR_KEY_SIZE}"
+        )));
+    }
+    if entries.iter().any(|&x| x >= Z257::MODULUS) {
+        return Err(Error::MalformedKey(
+            "a modular key must only contain integers in [0, 257)".to_string(),
+        ));
     }
 
-    Ok((n, key))
+    let key = ModularMatrix::from_vec(n, n, entries.into_iter().map(|x| Z257::new(x as u64)).collect())?;
+    if (0..n).any(|i| key[i][i] == Z257::ZERO) {
+        return Err(Error::SingularKey);
+    }
+
+    Ok(key)
 }
 
-/// Stores the key matrix to the given file.
-pub fn store_key(file_path: &str, key: &FloatMatrix) -> io::Result<()> {
-    let mut file = File::create(Path::new(file_path))?;
+/// Parses a key file of the legacy format. It does not record the kind of the key, so
+/// keys with whole number entries are reported as `KeyKind::Integer`.
+fn parse_legacy_key(buf: &[u8]) -> Result<(KeyMetadata, Key)> {
+    // Read the first usize - the size of the key.
+    if buf.len() < 8 {
+        return Err(Error::MalformedKey("the file is too short to contain a header".to_string()));
+    }
+    let n = u64::from_be_bytes(buf[..8].try_into().unwrap()) as usize;
+
+    if n == 0 {
+        return Err(Error::MalformedKey("the key size is zero".to_string()));
+    }
+    let expected_len = key_body_len(n, 4).and_then(|len| len.checked_add(8));
+    if expected_len.is_none_or(|len| buf.len() < len) {
+        return Err(Error::MalformedKey(format!(
+            "the file is too short for a {n}x{n} key"
+        )));
+    }
 
-    let header = key.n.to_be_bytes();
-    let body = key
-        .data
-        .iter()
-        .flat_map(|row| row.iter().map(|elt| elt.to_be_bytes()).collect::<Vec<_>>())
-        .collect::<Vec<_>>()
-        .concat();
+    let data = buf[8..8 + n * n * 4]
+        .chunks_exact(4)
+        .map(|bytes| f32::from_be_bytes(bytes.try_into().unwrap()))
+        .collect::<Vec<_>>();
 
-    let data = [header.to_vec(), body].concat();
+    let kind = if data.iter().all(|x| x.fract() == 0.0) {
+        KeyKind::Integer
+    } else {
+        KeyKind::Float
+    };
+    let metadata = KeyMetadata {
+        version: 0,
+        element: KeyElement::F32,
+        kind,
+        created: None,
+    };
+
+    Ok((metadata, float_key(kind, FloatMatrix::from_vec(n, n, data)?)))
+}
 
-    file.write(data.as_slice())?;
+/// The key of the float cipher of the given kind.
+fn float_key(kind: KeyKind, key: FloatMatrix) -> Key {
+    match kind {
+        KeyKind::Integer => Key::Integer(key),
+        KeyKind::Float | KeyKind::Modular => Key::Float(key),
+    }
+}
+
+/// Stores the key matrix to the given file, see `parse_key` for the format.
+pub fn store_key(file_path: &str, key: &Key) -> Result<()> {
+    let kind = key.kind();
+    let (n, m, element, entries) = match key {
+        Key::Float(key) | Key::Integer(key) => (key.n, key.m, KeyElement::F32, key.data.iter().map(|x| x.to_bits()).collect::<Vec<_>>()),
+        Key::Modular(key) => (key.n, key.m, KeyElement::U32, key.data.iter().map(|x| x.value()).collect()),
+    };
+    if n != m {
+        return Err(Error::DimensionMismatch {
+            expected: (n, n),
+            found: (n, m),
+        });
+    }
+
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    let mut data = Vec::with_capacity(KEY_HEADER_LEN + entries.len() * 4 + KEY_CHECKSUM_LEN);
+    data.extend_from_slice(&KEY_MAGIC);
+    data.extend_from_slice(&KEY_FORMAT_VERSION.to_be_bytes());
+    data.push(element.to_byte());
+    data.push(kind.to_byte());
+    data.extend_from_slice(&created.to_be_bytes());
+    data.extend_from_slice(&(n as u64).to_be_bytes());
+    for elt in entries {
+        data.extend_from_slice(&elt.to_be_bytes());
+    }
+    data.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+
+    let mut file = File::create(Path::new(file_path))?;
+    file.write_all(data.as_slice())?;
 
     Ok(())
 }
 
-/// Multiply `data` matrix by `(L+I)(U+I)` where `L + U + I = key`
-pub fn encrypt_matrix_with_key(key: &FloatMatrix, data: &FloatMatrix) -> FloatMatrix {
-    assert!(key.m == data.n);
+/// Identifies a key without revealing it: the first 8 bytes of the SHA-256 of the size and
+/// the entries of the key, as big-endian `f32`s (the representatives, for modular keys).
+pub fn key_fingerprint(key: &Key) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"cp1-fingerprint");
+    hasher.update((key.n() as u64).to_be_bytes());
+    match key {
+        Key::Float(key) | Key::Integer(key) => key.data.iter().for_each(|elt| hasher.update(elt.to_be_bytes())),
+        Key::Modular(key) => key.data.iter().for_each(|elt| hasher.update((elt.value() as f32).to_be_bytes())),
+    }
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
 
-    let k = key.tril().dot(&key.triu());
+/// Multiply `data` matrix by `(L+I)(U+I)` where `L + U + I = key`
+pub fn encrypt_matrix_with_key<T: MatrixElement>(key: &Matrix<T>, data: &Matrix<T>) -> Result<Matrix<T>> {
+    let k = key.tril().dot(&key.triu())?;
 
     // println!("Start multiplication...");
     // println!("k: {}x{}", k.n, k.m);
     // println!("data: {}x{}", data.n, data.m);
     
-    let res = k.dot(&data);
+    let res = k.dot(data)?;
     
     // println!("done");
     
-    res
+    Ok(res)
+}
+
+/// Returns an error unless `key` is a non-empty square matrix.
+fn check_key_shape<T: MatrixElement>(key: &Matrix<T>) -> Result<()> {
+    if key.n == 0 {
+        return Err(Error::MalformedKey("the key is empty".to_string()));
+    }
+    if key.n != key.m {
+        return Err(Error::DimensionMismatch {
+            expected: (key.n, key.n),
+            found: (key.n, key.m),
+        });
+    }
+    Ok(())
+}
+
+/// Returns an error unless `key` is a non-empty square matrix with as many columns as `data` has rows.
+fn check_decryption_shapes<T: MatrixElement>(key: &Matrix<T>, data: &Matrix<T>) -> Result<()> {
+    check_key_shape(key)?;
+    if key.m != data.n {
+        return Err(Error::DimensionMismatch {
+            expected: (key.m, data.m),
+            found: (data.n, data.m),
+        });
+    }
+    Ok(())
 }
 
-/// Decrypt the `data` matrix via a direct method. The method used is Thomas' algorithm (the best since I already have L and U stored).
-pub fn dectrypt_matrix_with_key_direct(key: &FloatMatrix, data: FloatMatrix) -> FloatMatrix {
+/// Decrypt the `data` matrix via a direct method. The method used is Thomas' algorithm (the best since I already have L and U stored),
+/// on all the columns at once.
+pub fn dectrypt_matrix_with_key_direct<T: MatrixElement>(key: &Matrix<T>, mut data: Matrix<T>) -> Result<Matrix<T>> {
     let l = key.tril();
     let u = key.triu();
     
-    let data = data.transpose();
+    check_decryption_shapes(key, &data)?;
 
     // println!("Start direct method...");
     // println!("\tk: {}x{}", key.n, key.m);
     // println!("\tdata: {}x{}", data.n, data.m);
     
-    // The decrypted encrypted input matrix.
-    let decrypted = FloatMatrix::from(
-        (0..data.n)
-            .into_par_iter()
-            .map(|i| {
-                FloatMatrix::solve_system_lu(&l, &u, data.data[i].clone())
-            })
-            .collect::<Vec<_>>(),
-    )
-    .transpose();
+    // Decrypt the columns in place.
+    Matrix::solve_system_lu_in_place(&l, &u, &mut data)?;
     
     // println!("done");
 
-    decrypted
+    Ok(data)
 }
 
-/// Decrypt the `data` matrix via an iterative method. The method used is SOR (Successive Over-Relaxation) with `omega = 1.3`
-pub fn dectrypt_matrix_with_key_iterative(key: &FloatMatrix, data: FloatMatrix, iterations: usize) -> FloatMatrix {
-    let k = key.tril().dot(&key.triu());
-
-    let data = data.transpose();
-    
+/// Decrypt the `data` matrix via an iterative method, SOR (Successive Over-Relaxation) with `omega = 1.3` by default,
+/// every column until its residual is within the tolerance. Returns the decrypted matrix and the report of its columns.
+///
+/// Builds the `IterativeSystem` for the key, so decrypting many blocks with the same key is
+/// cheaper with one `IterativeSystem` for all of them.
+pub fn dectrypt_matrix_with_key_iterative<T: FloatElement>(key: &Matrix<T>, data: Matrix<T>, options: &IterativeOptions) -> Result<(Matrix<T>, SolveReport)> {
     // println!("Start iterarive method...");
     // println!("\tk: {}x{}", key.n, key.m);
     // println!("\tdata: {}x{}", data.n, data.m);
-    
-    // The decrypted encrypted input matrix.
-    let decrypted = FloatMatrix::from(
-        (0..data.n)
-            .into_par_iter()
-            .map(|i| {
-                FloatMatrix::solve_system_iterative(&k, data.data[i].clone(), 1.3, iterations)
-            })
-            .collect::<Vec<_>>(),
-    )
-    .transpose();
-    
+
+    let system = IterativeSystem::new(key, options)?;
+    let (decrypted, columns) = system.solve(data)?;
+    let mut report = SolveReport::new(options);
+    report.record(system.omega(), &columns);
+
     // println!("done.");
-    
-    decrypted
-}
\ No newline at end of file
+
+    Ok((decrypted, report))
+}
+
+/// Whether the element is a byte. The modular cipher only ever outputs bytes: a column which has
+/// an element equal to 256 after the multiplication is encrypted again ("cycle walking"), until it's
+/// made of bytes only. Since `K` is a permutation of `Z_257^n`, this is a permutation of the vectors of bytes.
+fn is_byte(x: &Z257) -> bool {
+    x.value() < 256
+}
+
+/// Multiply the `data` matrix (of bytes) by `K = tril(key) * triu(key)` over `Z_257`, walking
+/// the columns which leave the bytes until they come back.
+pub fn encrypt_matrix_with_key_modular(key: &ModularMatrix, data: &ModularMatrix) -> Result<ModularMatrix> {
+    if !data.data.iter().all(is_byte) {
+        return Err(Error::UnsupportedFormat("the modular cipher can only encrypt bytes".to_string()));
+    }
+
+    let k = key.tril().dot(&key.triu())?;
+    // Every row of the transposed matrix is a column of the result, stored contiguously.
+    let mut res = k.dot(data)?.transpose();
+    if k.n == 0 {
+        return Ok(res.transpose());
+    }
+
+    res.data
+        .par_chunks_mut(k.n)
+        .try_for_each(|column| -> Result<()> {
+            while !column.iter().all(is_byte) {
+                let next = k.dot(&Matrix::from_vec(k.n, 1, column.to_vec())?)?;
+                column.copy_from_slice(&next.data);
+            }
+            Ok(())
+        })?;
+
+    Ok(res.transpose())
+}
+
+/// Decrypt the `data` matrix encrypted by `encrypt_matrix_with_key_modular`. Solves the systems with
+/// forward and back substitution, which is exact over `Z_257`, walking back until the columns are bytes.
+pub fn dectrypt_matrix_with_key_modular(key: &ModularMatrix, data: ModularMatrix) -> Result<ModularMatrix> {
+    let l = key.tril();
+    let u = key.triu();
+
+    check_decryption_shapes(key, &data)?;
+    if !data.data.iter().all(is_byte) {
+        return Err(Error::MalformedCiphertext("the modular ciphertext must only contain bytes".to_string()));
+    }
+    // Every row of the transposed matrix is a column of `data`, stored contiguously.
+    let mut data = data.transpose();
+
+    // Decrypt the columns in place.
+    data.data
+        .par_chunks_mut(key.n)
+        .try_for_each(|column| -> Result<()> {
+            loop {
+                let x = Matrix::solve_system_lu(&l, &u, column.to_vec())?;
+                column.copy_from_slice(&x);
+                if column.iter().all(is_byte) {
+                    return Ok(());
+                }
+            }
+        })?;
+
+    Ok(data.transpose())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modular_cipher_walks_the_columns_back_to_bytes() {
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let key = gen_modular_key_with_rng(8, &mut rng).unwrap();
+        let data = ModularMatrix::from_vec(8, 64, (0..8 * 64).map(|i| Z257::new(i % 256)).collect()).unwrap();
+
+        // Some columns leave the bytes after a single multiplication, so they are walked.
+        let k = key.tril().dot(&key.triu()).unwrap();
+        assert!(!k.dot(&data).unwrap().data.iter().all(is_byte));
+
+        let encrypted = encrypt_matrix_with_key_modular(&key, &data).unwrap();
+        assert!(encrypted.data.iter().all(is_byte));
+        assert!(encrypted != data);
+
+        let decrypted = dectrypt_matrix_with_key_modular(&key, encrypted).unwrap();
+        assert!(decrypted == data);
+    }
+
+    #[test]
+    fn empty_keys_are_invalid_arguments() {
+        assert!(matches!(gen_key(0, false), Err(Error::InvalidArgument(_))));
+        assert!(matches!(gen_modular_key(0), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn modular_keys_are_capped() {
+        assert!(gen_modular_key(MAX_MODULAR_KEY_SIZE).is_ok());
+        assert!(matches!(gen_modular_key(MAX_MODULAR_KEY_SIZE + 1), Err(Error::InvalidArgument(_))));
+
+        // A larger key file (written by hand or by an older version) is rejected when loaded.
+        let n = MAX_MODULAR_KEY_SIZE + 1;
+        let path = temp_path("large.key");
+        store_key(&path, &Key::Modular(ModularMatrix::identity(n))).unwrap();
+        let loaded = load_key(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(Error::MalformedKey(e)) if e.contains("at most")));
+    }
+
+    #[test]
+    fn modular_cipher_rejects_non_bytes() {
+        let key = gen_modular_key(4).unwrap();
+        let data = ModularMatrix::from_vec(4, 1, vec![Z257::new(256); 4]).unwrap();
+
+        assert!(matches!(encrypt_matrix_with_key_modular(&key, &data), Err(Error::UnsupportedFormat(_))));
+        assert!(matches!(dectrypt_matrix_with_key_modular(&key, data), Err(Error::MalformedCiphertext(_))));
+    }
+
+    #[test]
+    fn modular_keys_have_a_nonzero_diagonal() {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        for _ in 0..20 {
+            let key = gen_modular_key_with_rng(16, &mut rng).unwrap();
+            assert!((0..16).all(|i| key[i][i] != Z257::ZERO));
+        }
+    }
+
+    #[test]
+    fn derived_keys_only_depend_on_the_passphrase_and_the_seed() {
+        let rngs = [derive_key_rng("open sesame", 1), derive_key_rng("open sesame", 1), derive_key_rng("open sesame", 2)];
+        let float = rngs.clone().map(|mut rng| gen_key_with_rng(6, false, &mut rng).unwrap());
+        let modular = rngs.map(|mut rng| gen_modular_key_with_rng(6, &mut rng).unwrap());
+
+        assert!(float[0] == float[1]);
+        assert!(float[0] != float[2]);
+        assert!(modular[0] == modular[1]);
+        assert!(modular[0] != modular[2]);
+    }
+
+    #[test]
+    fn auto_omega_is_optimal_for_known_spectral_radii() {
+        let optimal = |rho: f64| 2.0 / (1.0 + (1.0 - rho * rho).sqrt());
+        // The Jacobi iteration matrices have the eigenvalues `+-1/2`, and `+-1/sqrt(2)` and 0.
+        let systems = [
+            (Matrix::<f64>::from_vec(2, 2, vec![2.0, 1.0, 1.0, 2.0]).unwrap(), 0.5),
+            (
+                Matrix::<f64>::from_vec(3, 3, vec![2.0, -1.0, 0.0, -1.0, 2.0, -1.0, 0.0, -1.0, 2.0]).unwrap(),
+                std::f64::consts::FRAC_1_SQRT_2,
+            ),
+        ];
+        for (k, rho) in systems {
+            assert!((k.jacobi_spectral_radius().unwrap() - rho).abs() < 1e-9);
+            assert!((Omega::Auto.value(&k).unwrap() - optimal(rho)).abs() < 1e-9);
+        }
+
+        // `rho = 2`: the Jacobi iteration diverges, so it falls back to Gauss-Seidel.
+        let k = Matrix::<f64>::from_vec(2, 2, vec![1.0, 2.0, 2.0, 1.0]).unwrap();
+        assert_eq!(Omega::Auto.value(&k).unwrap(), 1.0);
+        assert_eq!(Omega::Fixed(1.7).value(&k).unwrap(), 1.7);
+    }
+
+    #[test]
+    fn iterative_options_are_validated() {
+        let options = |method, preconditioner, omega| {
+            IterativeOptions::new(method, preconditioner, omega, Tolerance::default())
+        };
+
+        assert!(options(IterativeMethod::Sor, None, Omega::Fixed(1.9)).is_ok());
+        assert!(options(IterativeMethod::Sor, None, Omega::Auto).is_ok());
+        for omega in [0.0, -1.0, 2.0, 2.5, f64::NAN] {
+            let result = options(IterativeMethod::Sor, None, Omega::Fixed(omega));
+            assert!(matches!(result, Err(Error::InvalidArgument(e)) if e.contains("omega")), "{omega}");
+        }
+
+        let preconditioned = |method| options(method, Some(Preconditioning::Ilu0), Omega::default());
+        assert!(preconditioned(IterativeMethod::BiCgStab).is_ok());
+        assert!(matches!(preconditioned(IterativeMethod::Sor), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn solve_reports_count_the_columns() {
+        let column = |iterations, residual, converged| Convergence { iterations, residual, converged };
+        let mut report = SolveReport::new(&IterativeOptions::default());
+        report.record(Some(1.3), &[column(4, 1e-7, true), column(100, 0.5, false)]);
+        let mut other = SolveReport::new(&IterativeOptions::default());
+        other.record(Some(1.5), &[column(100, 2.0, false), column(6, 1e-8, true)]);
+        report.merge(&other);
+
+        assert_eq!(report.columns, 4);
+        assert_eq!(report.converged, 2);
+        assert!(!report.converged());
+        assert_eq!(report.iterations, 210);
+        assert_eq!(report.max_iterations, 100);
+        assert_eq!(report.mean_iterations(), 52.5);
+        assert_eq!(report.max_residual, 2.0);
+        // The columns of the merged report come after the ones of the first.
+        assert_eq!(report.unconverged, [1, 2]);
+        assert_eq!(report.omega, [1.3, 1.5]);
+        assert_eq!(
+            report.to_json(),
+            "{\"method\":\"SOR\",\"omega\":[1.3,1.5],\"columns\":4,\"converged\":2,\"iterations\":210,\"max_iterations\":100,\"mean_iterations\":52.5,\"max_residual\":2,\"unconverged\":[1,2]}"
+        );
+
+        // A diverged column makes the largest residual `NaN`, which is not a JSON number.
+        report.record(Some(1.3), &[column(3, f64::NAN, false)]);
+        assert!(report.max_residual.is_nan());
+        assert!(report.to_json().contains("\"max_residual\":null"));
+        assert_eq!(report.unconverged, [1, 2, 4]);
+    }
+
+    /// A path in the temporary directory unique to the test.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("cp1-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn keys_roundtrip_through_key_files() {
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let keys = [
+            Key::Float(gen_key_with_rng(5, false, &mut rng).unwrap()),
+            Key::Integer(gen_key_with_rng(5, true, &mut rng).unwrap()),
+            Key::Modular(gen_modular_key_with_rng(5, &mut rng).unwrap()),
+            // The 1x1 float key is the identity, but it still is a float key.
+            Key::Float(gen_key_with_rng(1, false, &mut rng).unwrap()),
+        ];
+
+        let kinds = [KeyKind::Float, KeyKind::Integer, KeyKind::Modular, KeyKind::Float];
+        for (key, kind) in keys.iter().zip(kinds) {
+            let path = temp_path("roundtrip.key");
+            store_key(&path, key).unwrap();
+            let (metadata, loaded) = load_key_with_metadata(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(loaded == *key);
+            assert_eq!(metadata.version, KEY_FORMAT_VERSION);
+            assert_eq!(metadata.kind, kind);
+            assert_eq!(loaded.kind(), kind);
+            let element = if kind == KeyKind::Modular { KeyElement::U32 } else { KeyElement::F32 };
+            assert_eq!(metadata.element, element);
+            assert!(metadata.created.is_some());
+        }
+    }
+
+    #[test]
+    fn corrupted_key_files_are_rejected() {
+        let key = Key::Float(gen_key(4, false).unwrap());
+        let path = temp_path("corrupted.key");
+        store_key(&path, &key).unwrap();
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_key(&buf).is_ok());
+
+        // Any flipped bit fails the checksum, unless it already makes the header invalid.
+        for i in [KEY_HEADER_LEN, KEY_HEADER_LEN + 17, buf.len() - 1] {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x10;
+            assert!(matches!(parse_key(&corrupted), Err(Error::MalformedKey(e)) if e.contains("checksum")));
+        }
+
+        let mut version = buf.clone();
+        version[5] = 9;
+        assert!(matches!(parse_key(&version), Err(Error::MalformedKey(e)) if e.contains("version")));
+        assert!(matches!(parse_key(&buf[..buf.len() - 1]), Err(Error::MalformedKey(_))));
+        assert!(matches!(parse_key(&buf[..KEY_HEADER_LEN]), Err(Error::MalformedKey(_))));
+    }
+
+    #[test]
+    fn legacy_key_files_are_still_read() {
+        let entries = [1.0f32, 0.25, 0.5, 1.0];
+        let mut buf = 2u64.to_be_bytes().to_vec();
+        for entry in entries {
+            buf.extend_from_slice(&entry.to_be_bytes());
+        }
+        let path = temp_path("legacy.key");
+        std::fs::write(&path, &buf).unwrap();
+        let (metadata, key) = load_key_with_metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(metadata.version, 0);
+        assert_eq!(metadata.kind, KeyKind::Float);
+        assert_eq!(metadata.created, None);
+        assert!(key == Key::Float(FloatMatrix::from_vec(2, 2, entries.to_vec()).unwrap()));
+
+        // Keys with whole number entries are reported as integer keys.
+        let mut whole = 2u64.to_be_bytes().to_vec();
+        for entry in [3.0f32, 1.0, 0.0, 3.0] {
+            whole.extend_from_slice(&entry.to_be_bytes());
+        }
+        assert_eq!(parse_legacy_key(&whole).unwrap().0.kind, KeyKind::Integer);
+        assert!(matches!(parse_legacy_key(&whole[..20]), Err(Error::MalformedKey(_))));
+        assert!(matches!(parse_legacy_key(&0u64.to_be_bytes()), Err(Error::MalformedKey(_))));
+    }
+}
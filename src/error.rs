@@ -0,0 +1,77 @@
+use std::{fmt::Display, io};
+
+/// ## Error
+/// Everything that can go wrong while generating keys, encrypting or decrypting.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// The key file is truncated or otherwise not a valid key.
+    MalformedKey(String),
+    /// The encrypted input could not be parsed.
+    MalformedCiphertext(String),
+    /// The input file is of a type/format that can not be handled.
+    UnsupportedFormat(String),
+    /// An option is invalid or does not go with the other options (or with the input).
+    InvalidArgument(String),
+    /// The shapes of the matrices involved in an operation do not agree.
+    DimensionMismatch {
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    /// The key matrix can not be used to solve the system (zero pivot).
+    SingularKey,
+    /// The ciphertext was encrypted with a different key.
+    KeyMismatch,
+    /// Some columns did not converge within the iterations of an iterative method, so the
+    /// decrypted file is not exact.
+    Unconverged {
+        columns: u64,
+        unconverged: u64,
+        iterations: usize,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {e}"),
+            Error::MalformedKey(msg) => write!(f, "malformed key: {msg}"),
+            Error::MalformedCiphertext(msg) => write!(f, "malformed ciphertext: {msg}"),
+            Error::UnsupportedFormat(msg) => write!(f, "unsupported format: {msg}"),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {msg}"),
+            Error::DimensionMismatch { expected, found } => write!(
+                f,
+                "dimension mismatch: expected {}x{}, found {}x{}",
+                expected.0, expected.1, found.0, found.1
+            ),
+            Error::SingularKey => write!(f, "the key matrix is singular"),
+            Error::KeyMismatch => write!(f, "the ciphertext was encrypted with a different key"),
+            Error::Unconverged { columns, unconverged, iterations } => write!(
+                f,
+                "{unconverged} of {columns} columns did not converge in {iterations} iterations, the decrypted file is not exact"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // Our own errors which had to pass through a `Read` or `Write` implementation.
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
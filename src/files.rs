@@ -0,0 +1,239 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    audio::{decrypt_audio_stream, encrypt_audio_stream, AudioOptions},
+    binary::{binary_extension, encrypt_binary_stream, file_extension, read_binary_header, BINARY_EXTENSION},
+    container::{is_container, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::Result,
+    stream::read_full,
+    text::{decrypt_text_stream, encrypt_text_stream},
+    SolutionMethod,
+};
+
+/// The file path which stands for the standard input (or output).
+pub const STDIO: &str = "-";
+/// The number of bytes looked at to tell what a file contains.
+const SNIFF_LEN: usize = 8192;
+
+/// Opens the file, or the standard input if the path is `-`, and returns it together with its
+/// length (if known).
+pub fn open_input(file_path: &str) -> Result<(Input, Option<u64>)> {
+    if file_path == STDIO {
+        return Ok((Input::Stdin(io::Cursor::new(Vec::new()), BufReader::new(io::stdin())), None));
+    }
+
+    let file = File::open(Path::new(file_path))?;
+    let metadata = file.metadata()?;
+    let length = metadata.is_file().then_some(metadata.len());
+    Ok((Input::File(BufReader::new(file)), length))
+}
+
+/// ## Input
+/// A file, which can seek (so that e.g. `--range` decrypts without keeping the samples in
+/// memory), or the standard input, which can not.
+pub enum Input {
+    File(BufReader<File>),
+    /// The bytes put back by `peek` and the rest of the standard input.
+    Stdin(io::Cursor<Vec<u8>>, BufReader<io::Stdin>),
+}
+
+impl Input {
+    /// Reads the next `len` bytes (fewer if the input ends first) and puts them back.
+    pub fn peek(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut head = vec![0; len];
+        let read = read_full(self, &mut head)?;
+        head.truncate(read);
+        match self {
+            Input::File(file) => file.seek_relative(-(read as i64))?,
+            Input::Stdin(pending, _) => {
+                let rest = &pending.get_ref()[pending.position() as usize..];
+                *pending = io::Cursor::new([head.as_slice(), rest].concat());
+            }
+        }
+        Ok(head)
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Stdin(pending, stdin) => match pending.read(buf)? {
+                0 => stdin.read(buf),
+                read => Ok(read),
+            },
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(file) => file.seek(position),
+            Input::Stdin(..) => Err(io::Error::new(io::ErrorKind::Unsupported, "the standard input can not seek")),
+        }
+    }
+}
+
+/// Writes to the file, or to the standard output if the path is `-`. The file is only created
+/// on the first write, so that nothing is left behind when the input is rejected up front.
+pub struct Output {
+    file_path: String,
+    inner: Option<Box<dyn Write>>,
+}
+
+impl Output {
+    pub fn new(file_path: String) -> Self {
+        Output { file_path, inner: None }
+    }
+
+    fn inner(&mut self) -> io::Result<&mut Box<dyn Write>> {
+        if self.inner.is_none() {
+            let inner: Box<dyn Write> = if self.file_path == STDIO {
+                Box::new(BufWriter::new(io::stdout()))
+            } else {
+                Box::new(BufWriter::new(File::create(Path::new(&self.file_path))?))
+            };
+            self.inner = Some(inner);
+        }
+        Ok(self.inner.as_mut().unwrap())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // An empty result is still a result.
+        self.inner()?.flush()
+    }
+}
+
+/// Encrypts the given file (`-` for the standard input) with the given key in the given mode
+/// and returns what it contained. `.wav` files are encrypted as audio, UTF-8 `.txt` files (and
+/// UTF-8 from the standard input) as text and anything else as binary, so that the extension of
+/// the file is kept.
+///
+/// The result is written to `output` (`-` for the standard output). By default it is stored next
+/// to the file as `<name>-encrypted.txt`, `<name>-encrypted.wav` or `<name>-encrypted.bin`, or
+/// written to the standard output if the file was read from the standard input.
+///
+/// The file is encrypted while it is read, so the memory used does not depend on its length.
+/// `audio` only applies to `.wav` files.
+pub fn encrypt_with_key(
+    file_path: &str,
+    output: Option<&str>,
+    key: &Key,
+    mode: Mode,
+    audio: &AudioOptions,
+) -> Result<PayloadType> {
+    key.check_mode(mode)?;
+    let (mut reader, length) = open_input(file_path)?;
+    let head = reader.peek(SNIFF_LEN)?;
+
+    let payload = if is_wav(&head) {
+        let mut out = Output::new(output_path(file_path, output, "encrypted", "wav"));
+        encrypt_audio_stream(&mut reader, &mut out, key, mode, audio)?;
+        out.flush()?;
+        PayloadType::Audio
+    } else if is_text_file(file_path) && is_text(&head) {
+        let mut out = Output::new(output_path(file_path, output, "encrypted", "txt"));
+        encrypt_text_stream(&mut reader, length, &mut out, key, mode)?;
+        out.flush()?;
+        PayloadType::Text
+    } else {
+        let extension = if file_path == STDIO { None } else { file_extension(file_path) };
+        let mut out = Output::new(output_path(file_path, output, "encrypted", BINARY_EXTENSION));
+        encrypt_binary_stream(&mut reader, length, extension, &mut out, key, mode)?;
+        out.flush()?;
+        PayloadType::Binary
+    };
+
+    Ok(payload)
+}
+
+/// Decrypts the given encrypted file (`-` for the standard input) with the given key and returns
+/// what it contained. Whether it is text, audio or a binary file is decided by the contents of
+/// the file rather than its name.
+///
+/// The result is written to `output` like in `encrypt_with_key`, by default as
+/// `<name>-decrypted.txt`, `<name>-decrypted.wav` or `<name>-decrypted.<extension>` with the
+/// extension of the original binary file. Also returns the report of the iterative method
+/// (`None` for the direct one).
+pub fn decrypt_with_key(
+    file_path: &str,
+    output: Option<&str>,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<(PayloadType, Option<SolveReport>)> {
+    let (mut reader, _) = open_input(file_path)?;
+    let head = &reader.peek(4)?;
+
+    // Encrypted audio is a `.wav` file, an encrypted binary file is a bare container and
+    // encrypted text only contains letters.
+    let decrypted = if is_wav(head) {
+        let mut out = Output::new(output_path(file_path, output, "decrypted", "wav"));
+        let report = decrypt_audio_stream(&mut reader, &mut out, key, method)?;
+        out.flush()?;
+        (PayloadType::Audio, report)
+    } else if is_container(head) {
+        let header = read_binary_header(&mut reader)?;
+        let extension = binary_extension(&header);
+        let mut out = Output::new(output_path(file_path, output, "decrypted", &extension));
+        let report = header.open(key, method, &mut reader, &mut out)?;
+        out.flush()?;
+        (PayloadType::Binary, report)
+    } else {
+        let mut out = Output::new(output_path(file_path, output, "decrypted", "txt"));
+        let report = decrypt_text_stream(&mut reader, &mut out, key, method)?;
+        out.flush()?;
+        (PayloadType::Text, report)
+    };
+
+    Ok(decrypted)
+}
+
+/// `.wav` files start with a RIFF chunk.
+fn is_wav(buf: &[u8]) -> bool {
+    buf.starts_with(b"RIFF")
+}
+
+/// Whether the file is a text file by its name: a `.txt` file, or the standard input (which has
+/// no extension to keep).
+fn is_text_file(file_path: &str) -> bool {
+    file_path == STDIO || file_extension(file_path).is_some_and(|ext| ext.eq_ignore_ascii_case("txt"))
+}
+
+/// Whether the start of a file is UTF-8 (except maybe for a character cut in half at the end).
+fn is_text(head: &[u8]) -> bool {
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() == SNIFF_LEN,
+    }
+}
+
+/// The given output path, or `<name>-<suffix>.<extension>` next to the input file, where
+/// `<name>` is the file name without its extension (the standard output for the standard input).
+fn output_path(file_path: &str, output: Option<&str>, suffix: &str, extension: &str) -> String {
+    if let Some(output) = output {
+        return output.to_string();
+    }
+    if file_path == STDIO {
+        return STDIO.to_string();
+    }
+
+    let path = Path::new(file_path);
+    let name = path
+        .file_stem()
+        .map_or_else(|| file_path.into(), |stem| stem.to_string_lossy());
+    path.with_file_name(format!("{name}-{suffix}.{extension}"))
+        .to_string_lossy()
+        .into_owned()
+}
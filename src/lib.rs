@@ -0,0 +1,44 @@
+//! # cp1
+//! Encryption and decryption of `txt`/`wav` (or any other) files using ordinary linear algebra.
+//!
+//! The key is a square matrix `key = L + U + I` and the data is encrypted by multiplying
+//! it with `K = (L + I)(U + I)`. See the `README.md` for the details of the pipelines.
+
+pub mod audio;
+pub mod binary;
+pub mod channels;
+pub mod compare;
+pub mod container;
+pub mod encryption;
+pub mod error;
+pub mod files;
+pub mod matrix;
+pub mod metadata;
+pub mod riff;
+pub mod scramble;
+pub mod segments;
+pub mod stream;
+pub mod text;
+
+pub use audio::{decrypt_audio_with_key, encrypt_audio_with_key, AudioOptions};
+pub use binary::{decrypt_binary_with_key, encrypt_binary_with_key};
+pub use channels::ChannelSelection;
+pub use compare::{compare_files, Comparison};
+pub use container::{ContainerHeader, PayloadType, SolverHints};
+pub use encryption::{
+    dectrypt_matrix_with_key_direct, dectrypt_matrix_with_key_iterative,
+    dectrypt_matrix_with_key_modular, derive_key_rng, encrypt_matrix_with_key,
+    encrypt_matrix_with_key_modular, gen_key, gen_key_with_rng, gen_modular_key,
+    gen_modular_key_with_rng, key_fingerprint, load_key, load_key_with_metadata, store_key,
+    IterativeMethod, IterativeOptions, IterativeSystem, Key, KeyElement, KeyKind, KeyMetadata,
+    Mode, Omega, Precision, Preconditioning, SolutionMethod, SolveReport,
+};
+pub use error::{Error, Result};
+pub use files::{decrypt_with_key, encrypt_with_key};
+pub use matrix::{
+    BiCgStab, ConjugateGradient, Convergence, DoubleMatrix, FloatElement, FloatMatrix, GaussSeidel,
+    Gmres, Jacobi, JacobiPreconditioner, LuPreconditioner, Matrix, MatrixElement, ModularMatrix,
+    Preconditioner, Solver, Sor, Ssor, Tolerance, Zp, Z257,
+};
+pub use segments::TimeRange;
+pub use text::{decrypt_text_with_key, encrypt_text_with_key};
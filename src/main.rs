@@ -1,14 +1,62 @@
-mod audio;
-mod matrix;
-mod text;
-mod encryption;
+use std::process::ExitCode;
 
-use clap::{Parser, Subcommand};
-use encryption::{gen_key, store_key, load_key, SolutionMethod};
-use text::{decrypt_text_with_key, encrypt_text_with_key};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use rand::RngCore;
+use cp1::{
+    compare_files, decrypt_with_key, encrypt_with_key, AudioOptions, ChannelSelection, TimeRange,
+    derive_key_rng, gen_key_with_rng, gen_modular_key_with_rng, load_key, store_key, Error,
+    Key, Mode, Precision, Result, IterativeMethod, IterativeOptions, Omega, Preconditioning, SolutionMethod, Tolerance,
+};
 
-use crate::audio::{decrypt_audio_with_key, encrypt_audio_with_key};
+/// The kind of arithmetic the cipher uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CipherMode {
+    /// Nibbles multiplied by a real matrix.
+    Float,
+    /// Bytes multiplied by a matrix over the integers modulo 257 (exact and size-preserving).
+    Modular,
+}
+
+/// How the decrypting side solves `Kx = b`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DecryptionMethod {
+    /// Forward and back substitution with the triangular factors of the key.
+    Direct,
+    /// Successive over-relaxation.
+    Sor,
+    /// Jacobi iteration.
+    Jacobi,
+    /// Gauss-Seidel iteration.
+    GaussSeidel,
+    /// Symmetric successive over-relaxation.
+    Ssor,
+    /// Conjugate gradient on the normal equations.
+    Cg,
+    /// Restarted GMRES (see `--restart`).
+    Gmres,
+    /// Biconjugate gradient stabilized.
+    Bicgstab,
+}
+
+/// What the Krylov methods are preconditioned with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PreconditionerKind {
+    /// The diagonal of the system matrix.
+    Jacobi,
+    /// The incomplete LU factorization of the system matrix.
+    Ilu0,
+    /// Symmetric successive over-relaxation (see `--omega`).
+    Ssor,
+    /// The triangular factors of the key (the system matrix itself, a single iteration).
+    Exact,
+}
 
+/// How the report of the iterative method is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReportFormat {
+    Text,
+    Json,
+}
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -19,6 +67,7 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Generate an encryption key.
+    #[command(group(ArgGroup::new("passphrase_source").multiple(true)))]
     GenKey {
         /// The path of the key file.
         #[arg(short, long)]
@@ -31,6 +80,25 @@ enum Commands {
         /// only whole number entries.
         #[arg(short, long)]
         integer: bool,
+
+        /// The kind of cipher the key is for.
+        #[arg(short, long, value_enum, default_value_t = CipherMode::Float)]
+        mode: CipherMode,
+
+        /// Derive the key from a passphrase instead of generating a random one. Prefer the
+        /// environment variable or `--passphrase-file`: arguments are visible to other users (in
+        /// `ps`) and end up in the shell history.
+        #[arg(short, long, env = "CP1_PASSPHRASE", hide_env_values = true, group = "passphrase_source")]
+        passphrase: Option<String>,
+
+        /// Read the passphrase from the first line of a file instead (takes precedence over the
+        /// other two).
+        #[arg(long, group = "passphrase_source")]
+        passphrase_file: Option<String>,
+
+        /// The seed the key is derived with (needs a passphrase).
+        #[arg(short, long, requires = "passphrase_source")]
+        seed: Option<u64>,
     },
     /// Encrypte a (txt/wav) file with the specified key.
     Encrypt {
@@ -38,94 +106,276 @@ enum Commands {
         #[arg(short, long)]
         key_path: String,
         
-        /// The path to the file to be encrypted.
+        /// The path to the file to be encrypted (`-` for the standard input).
         #[arg(short, long)]
         file_path: String,
-    },
-    /// Decrypt a (txt/wav) file with the specified key via direct method.
-    DecryptDirect {
-        /// The path of the key file.
-        #[arg(short, long)]
-        key_path: String,
-        
-        /// The path to the file to be decrypted.
+
+        /// Where to store the encrypted file (`-` for the standard output).
+        /// Defaults to `<name>-encrypted.<txt/wav>` next to the file.
         #[arg(short, long)]
-        file_path: String
+        output: Option<String>,
+
+        /// The floating point precision used for the encrypted numbers (`f32` or `f64`).
+        #[arg(short, long, default_value_t = Precision::Single)]
+        precision: Precision,
+
+        /// The kind of cipher to encrypt with (the key must have been generated for it).
+        /// Defaults to the kind the key was generated for.
+        #[arg(short, long, value_enum)]
+        mode: Option<CipherMode>,
+
+        /// Encrypt the channels of `.wav` files separately, each with its own slice of the key:
+        /// `all` of them, or only the listed ones (numbered from 1, e.g. `1,3`), leaving the
+        /// others in the clear.
+        #[arg(long)]
+        channels: Option<ChannelSelection>,
+
+        /// Only encrypt this part of a `.wav` file (`<start>-<end>`, e.g. `00:01.5-00:03.0`),
+        /// leaving the rest playable. Can be given several times.
+        #[arg(long = "range")]
+        ranges: Vec<TimeRange>,
+
+        /// Store encrypted `.wav` files as noise just as long as the original (needs the
+        /// modular mode), so they still pass through audio tools.
+        #[arg(long)]
+        scramble: bool,
+
+        /// Encrypt the metadata chunks of `.wav` files (`LIST`, `cue `, ...) too, instead of
+        /// copying them as they are.
+        #[arg(long)]
+        encrypt_metadata: bool,
     },
-    /// Decrypt a (txt/wav) file with the specified key via iterative method.
-    DecryptIterative {
+    /// Decrypt a file encrypted with the specified key (text or audio, whatever its name).
+    Decrypt {
         /// The path of the key file.
         #[arg(short, long)]
         key_path: String,
         
-        /// The path to the file to be decrypted.
+        /// The path to the file to be decrypted (`-` for the standard input).
         #[arg(short, long)]
         file_path: String,
+
+        /// Where to store the decrypted file (`-` for the standard output).
+        /// Defaults to `<name>-decrypted.<txt/wav>` next to the file.
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// How the systems of equations are solved.
+        #[arg(short, long, value_enum, default_value_t = DecryptionMethod::Direct)]
+        method: DecryptionMethod,
         
-        /// Number of iteratioins for the iterative method.
+        /// Number of iteratioins for the iterative methods.
+        #[arg(short, long, default_value_t = 100)]
+        iterations: usize,
+
+        /// The number of iterations after which GMRES restarts.
+        #[arg(long, default_value_t = 30)]
+        restart: usize,
+
+        /// Precondition CG, GMRES or BiCGSTAB.
+        #[arg(short, long, value_enum)]
+        preconditioner: Option<PreconditionerKind>,
+
+        /// The relaxation parameter of SOR and SSOR (and of the SSOR preconditioner): a number
+        /// in (0, 2), or `auto` to pick the optimal one (for SOR) for the key.
+        #[arg(long, default_value_t = Omega::default())]
+        omega: Omega,
+
+        /// Stop iterating once the residual `||b - Kx||` is at most this much times `||b||`.
+        #[arg(short, long, default_value_t = Tolerance::default().relative)]
+        tolerance: f64,
+
+        /// Stop iterating once the residual `||b - Kx||` is at most this much.
+        #[arg(long, default_value_t = Tolerance::default().absolute)]
+        abs_tolerance: f64,
+
+        /// Print how the iterations went (to the standard error) as text or JSON.
+        #[arg(short, long, value_enum)]
+        report: Option<ReportFormat>,
+
+        /// Only warn (instead of failing) if some columns did not converge.
+        #[arg(long)]
+        allow_unconverged: bool,
+    },
+    /// Compare a decrypted (wav/txt) file with the original one, sample by sample.
+    Compare {
+        /// The path of the original file.
         #[arg(short, long)]
-        iterations: Option<usize>,
+        original: String,
+
+        /// The path of the decrypted file (`-` for the standard input).
+        #[arg(short, long)]
+        decrypted: String,
     },
 }
 
-fn main() {
-    let Args { cmd } = Args::parse();
+/// Maps every kind of error to its own process exit code.
+fn exit_code(error: &Error) -> u8 {
+    match error {
+        Error::Io(_) => 2,
+        Error::MalformedKey(_) => 3,
+        Error::MalformedCiphertext(_) => 4,
+        Error::UnsupportedFormat(_) => 5,
+        Error::DimensionMismatch { .. } => 6,
+        Error::SingularKey => 7,
+        Error::KeyMismatch => 8,
+        Error::Unconverged { .. } => 9,
+        Error::InvalidArgument(_) => 10,
+    }
+}
 
+/// Reads the passphrase from the first line of the file (without the line break).
+fn read_passphrase(path: &str) -> Result<String> {
+    let contents = std::fs::read_to_string(path)?;
+    let line = contents.lines().next().unwrap_or_default();
+    if line.is_empty() {
+        return Err(Error::InvalidArgument(format!("the passphrase file {path} is empty")));
+    }
+    Ok(line.to_string())
+}
+
+fn run(cmd: Commands) -> Result<()> {
     match cmd {
         Commands::GenKey {
             key_path,
             chunk_size,
-            integer
+            integer,
+            mode,
+            passphrase,
+            passphrase_file,
+            seed,
         } => {
-            let key = gen_key(chunk_size, integer);
+            let passphrase = match passphrase_file {
+                Some(path) => Some(read_passphrase(&path)?),
+                None => passphrase,
+            };
+            if passphrase.as_deref() == Some("") {
+                return Err(Error::InvalidArgument("the passphrase is empty".to_string()));
+            }
+            // Keys derived from the same passphrase and seed are the same.
+            let mut rng: Box<dyn RngCore> = match passphrase {
+                Some(passphrase) => Box::new(derive_key_rng(&passphrase, seed.unwrap_or_default())),
+                None => Box::new(rand::thread_rng()),
+            };
+            let key = match mode {
+                CipherMode::Float if integer => Key::Integer(gen_key_with_rng(chunk_size, true, &mut rng)?),
+                CipherMode::Float => Key::Float(gen_key_with_rng(chunk_size, false, &mut rng)?),
+                CipherMode::Modular => Key::Modular(gen_modular_key_with_rng(chunk_size, &mut rng)?),
+            };
             if chunk_size <= 30 {
-                println!("{}", key);
-                println!("\n{}", key.tril().dot(&key.triu()).round((chunk_size.ilog10() as usize + 2) * 2));
+                match &key {
+                    Key::Float(key) | Key::Integer(key) => {
+                        println!("{}", key);
+                        println!("\n{}", key.tril().dot(&key.triu())?.round((chunk_size.ilog10() as usize + 2) * 2));
+                    }
+                    Key::Modular(key) => {
+                        println!("{}", key);
+                        println!("\n{}", key.tril().dot(&key.triu())?);
+                    }
+                }
             }
-            store_key(key_path.as_str(), &key).unwrap();
+            store_key(key_path.as_str(), &key)?;
         }
         Commands::Encrypt {
             key_path,
             file_path,
+            output,
+            precision,
+            mode,
+            channels,
+            ranges,
+            scramble,
+            encrypt_metadata,
         } => {
-            let (_, key) = load_key(key_path.as_str()).unwrap();
-
-            if file_path.ends_with(".txt") {
-                encrypt_text_with_key(file_path.as_str(), &key).unwrap();
-            } else if file_path.ends_with(".wav") {
-                encrypt_audio_with_key(file_path.as_str(), &key).unwrap();
-            } else {
-                panic!("Invalid file type!");
-            }
-        },
-        Commands::DecryptDirect {
-            key_path,
-            file_path
-        } => {
-            let (_, key) = load_key(key_path.as_str()).unwrap();
-
-            if file_path.ends_with(".txt") {
-                decrypt_text_with_key(file_path.as_str(), &key, SolutionMethod::Direct).unwrap();
-            } else if file_path.ends_with(".wav") {
-                decrypt_audio_with_key(file_path.as_str(), &key, SolutionMethod::Direct).unwrap();
-            } else {
-                panic!("Invalid file type!");
+            let (_, key) = load_key(key_path.as_str())?;
+            let mode = match (mode, &key) {
+                (Some(CipherMode::Float), _) => Mode::Float(precision),
+                (Some(CipherMode::Modular), _) | (None, Key::Modular(_)) => Mode::Modular,
+                (None, Key::Float(_) | Key::Integer(_)) => Mode::Float(precision),
+            };
+            if mode == Mode::Modular && matches!(precision, Precision::Double) {
+                return Err(Error::InvalidArgument(
+                    "--precision only applies to the float mode".to_string(),
+                ));
             }
+
+            let audio = AudioOptions { channels, ranges, scramble, encrypt_metadata };
+            encrypt_with_key(file_path.as_str(), output.as_deref(), &key, mode, &audio)?;
         },
-        Commands::DecryptIterative {
+        Commands::Decrypt {
             key_path,
             file_path,
+            output,
+            method,
             iterations,
+            restart,
+            preconditioner,
+            omega,
+            tolerance,
+            abs_tolerance,
+            report,
+            allow_unconverged,
         } => {
-            let (_, key) = load_key(key_path.as_str()).unwrap();
-
-            if file_path.ends_with(".txt") {
-                decrypt_text_with_key(file_path.as_str(), &key, SolutionMethod::Iterative(iterations.unwrap_or(100))).unwrap();
-            } else if file_path.ends_with(".wav") {
-                decrypt_audio_with_key(file_path.as_str(), &key, SolutionMethod::Iterative(iterations.unwrap_or(100))).unwrap();
-            } else {
-                panic!("Invalid file type!");
+            let (_, key) = load_key(key_path.as_str())?;
+            let tolerance = Tolerance { absolute: abs_tolerance, relative: tolerance, max_iterations: iterations };
+            let preconditioner = preconditioner.map(|kind| match kind {
+                PreconditionerKind::Jacobi => Preconditioning::Jacobi,
+                PreconditionerKind::Ilu0 => Preconditioning::Ilu0,
+                PreconditionerKind::Ssor => Preconditioning::Ssor,
+                PreconditionerKind::Exact => Preconditioning::Exact,
+            });
+            let iterative = |method| IterativeOptions::new(method, preconditioner, omega, tolerance).map(SolutionMethod::Iterative);
+            let method = match method {
+                DecryptionMethod::Direct if preconditioner.is_some() => {
+                    return Err(Error::InvalidArgument("direct decryption can not be preconditioned".to_string()))
+                }
+                DecryptionMethod::Direct => SolutionMethod::Direct,
+                DecryptionMethod::Sor => iterative(IterativeMethod::Sor)?,
+                DecryptionMethod::Jacobi => iterative(IterativeMethod::Jacobi)?,
+                DecryptionMethod::GaussSeidel => iterative(IterativeMethod::GaussSeidel)?,
+                DecryptionMethod::Ssor => iterative(IterativeMethod::Ssor)?,
+                DecryptionMethod::Cg => iterative(IterativeMethod::ConjugateGradient)?,
+                DecryptionMethod::Gmres => iterative(IterativeMethod::Gmres(restart))?,
+                DecryptionMethod::Bicgstab => iterative(IterativeMethod::BiCgStab)?,
+            };
+
+            let (_, solved) = decrypt_with_key(file_path.as_str(), output.as_deref(), &key, &method)?;
+
+            if let Some(solved) = solved {
+                match report {
+                    Some(ReportFormat::Text) => eprintln!("{solved}"),
+                    Some(ReportFormat::Json) => eprintln!("{}", solved.to_json()),
+                    None => {}
+                }
+                if !solved.converged() {
+                    let error = Error::Unconverged {
+                        columns: solved.columns,
+                        unconverged: solved.columns - solved.converged,
+                        iterations,
+                    };
+                    if !allow_unconverged {
+                        return Err(error);
+                    }
+                    eprintln!("warning: {error}");
+                }
             }
         }
+        Commands::Compare { original, decrypted } => {
+            println!("{}", compare_files(original.as_str(), decrypted.as_str())?);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let Args { cmd } = Args::parse();
+
+    match run(cmd) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::from(exit_code(&error))
+        }
     }
 }
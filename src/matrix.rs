@@ -5,16 +5,22 @@ use std::{
 };
 
 use pad::{Alignment, PadStr};
+use rand::Rng;
 use rayon::prelude::*;
 
+use crate::error::{Error, Result};
+
 pub trait RandomRange {
-    /// Returns a random value of the implementer type in the range of `min` and `max`
-    fn rand_range(min: &Self, max: &Self) -> Self;
+    /// Returns a random value of the implementer type in the range of `min` and `max`, drawn from `rng`.
+    fn rand_range<R: Rng + ?Sized>(rng: &mut R, min: &Self, max: &Self) -> Self;
 }
 
-pub trait Field {
+pub trait Field: Sized {
     const ZERO: Self;
     const ONE: Self;
+
+    /// Returns the multiplicative inverse, or `None` for zero.
+    fn inverse(&self) -> Option<Self>;
 }
 
 pub trait Exponent {
@@ -36,6 +42,7 @@ where
         + RandomRange
         + Clone
         + Copy
+        + PartialEq
         + Display
         + Exponent
         + Round
@@ -44,6 +51,18 @@ where
 {
 }
 
+/// Number of rows of the result computed together by `Matrix::dot`.
+const DOT_MC: usize = 64;
+/// Number of rows of the right-hand side packed at a time by `Matrix::dot`.
+const DOT_KC: usize = 128;
+/// Number of columns of the result computed together by `Matrix::dot`.
+const DOT_NC: usize = 512;
+/// Number of right-hand sides solved together by `Matrix::solve_system_lu_in_place`.
+const TRSM_NC: usize = 64;
+
+/// ## Matrix
+/// An `n`x`m` matrix stored in a single row-major buffer:
+/// the element at `(i, j)` lives at `data[i * m + j]`.
 #[derive(Clone, PartialEq)]
 pub struct Matrix<T>
 where
@@ -51,7 +70,7 @@ where
 {
     pub n: usize,
     pub m: usize,
-    pub data: Vec<Vec<T>>,
+    pub data: Vec<T>,
 }
 
 #[allow(dead_code)]
@@ -59,21 +78,92 @@ impl<T> Matrix<T>
 where
     T: MatrixElement,
 {
-    pub fn from(data: Vec<Vec<T>>) -> Self {
-        Self {
-            n: data.len(),
-            m: data[0].len(),
-            data,
+    /// Builds a matrix from its rows. All the rows must be of the same length.
+    pub fn from(data: Vec<Vec<T>>) -> Result<Self> {
+        let n = data.len();
+        let m = data.first().map_or(0, |row| row.len());
+
+        if let Some(row) = data.iter().find(|row| row.len() != m) {
+            return Err(Error::DimensionMismatch {
+                expected: (n, m),
+                found: (n, row.len()),
+            });
+        }
+
+        Ok(Self {
+            n,
+            m,
+            data: data.concat(),
+        })
+    }
+
+    /// Builds an `n`x`m` matrix from its elements in row-major order.
+    pub fn from_vec(n: usize, m: usize, data: Vec<T>) -> Result<Self> {
+        if n.checked_mul(m) != Some(data.len()) {
+            return Err(Error::DimensionMismatch {
+                expected: (n, m),
+                found: (1, data.len()),
+            });
+        }
+
+        Ok(Self { n, m, data })
+    }
+
+    /// Returns the `i`-th row.
+    ///
+    /// # Panics
+    /// If `i` is not less than the number of rows.
+    pub fn row(&self, i: usize) -> &[T] {
+        &self.data[i * self.m..(i + 1) * self.m]
+    }
+
+    /// Returns the `i`-th row mutably.
+    ///
+    /// # Panics
+    /// If `i` is not less than the number of rows.
+    pub fn row_mut(&mut self, i: usize) -> &mut [T] {
+        &mut self.data[i * self.m..(i + 1) * self.m]
+    }
+
+    /// Returns an iterator over the rows.
+    pub fn rows(&self) -> impl Iterator<Item = &[T]> {
+        (0..self.n).map(|i| self.row(i))
+    }
+
+    /// Applies `f` to every element, producing a matrix of the same shape.
+    pub fn map<U: MatrixElement>(&self, f: impl Fn(T) -> U) -> Matrix<U> {
+        Matrix {
+            n: self.n,
+            m: self.m,
+            data: self.data.iter().map(|&x| f(x)).collect(),
+        }
+    }
+
+    /// Returns an iterator over the elements of the `j`-th column.
+    ///
+    /// # Panics
+    /// If `j` is not less than the number of columns.
+    pub fn column(&self, j: usize) -> impl Iterator<Item = T> + '_ {
+        assert!(j < self.m, "column index out of bounds");
+        self.data.iter().skip(j).step_by(self.m).copied()
+    }
+
+    /// Returns an error unless `self` and `rhs` have the same shape.
+    fn check_same_shape(&self, rhs: &Self) -> Result<()> {
+        if self.m != rhs.m || self.n != rhs.n {
+            return Err(Error::DimensionMismatch {
+                expected: (self.n, self.m),
+                found: (rhs.n, rhs.m),
+            });
         }
+        Ok(())
     }
 
     pub fn zero(n: usize, m: usize) -> Self {
         Self {
             n,
             m,
-            data: (0..n)
-                .map(|_| (0..m).map(|_| T::ZERO).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
+            data: vec![T::ZERO; n * m],
         }
     }
 
@@ -81,49 +171,106 @@ where
         Self {
             n,
             m,
-            data: (0..n)
-                .map(|_| (0..m).map(|_| T::ONE).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
+            data: vec![T::ONE; n * m],
         }
     }
 
     pub fn identity(n: usize) -> Self {
+        let mut res = Self::zero(n, n);
+        for i in 0..n {
+            res[i][i] = T::ONE;
+        }
+        res
+    }
+
+    pub fn random(n: usize, m: usize, min: &T, max: &T) -> Self {
+        Self::random_with_rng(n, m, min, max, &mut rand::thread_rng())
+    }
+
+    /// Same as `random`, but the entries are drawn from `rng` (row by row),
+    /// so that a seeded `rng` always gives the same matrix.
+    pub fn random_with_rng<R: Rng + ?Sized>(n: usize, m: usize, min: &T, max: &T, rng: &mut R) -> Self {
         Self {
             n,
-            m: n,
-            data: (0..n)
-                .map(|i| {
-                    (0..n)
-                        .map(|j| T::from(if i == j { T::ONE } else { T::ZERO }))
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
+            m,
+            data: (0..n * m).map(|_| T::rand_range(rng, min, max)).collect(),
         }
     }
 
-    pub fn random(n: usize, m: usize, min: &T, max: &T) -> Self {
-        let mut res = Self::zero(n, m);
+    pub fn dot(&self, rhs: &Self) -> Result<Self> {
+        if self.m != rhs.n {
+            return Err(Error::DimensionMismatch {
+                expected: (self.m, rhs.m),
+                found: (rhs.n, rhs.m),
+            });
+        }
 
-        for i in 0..n {
-            for j in 0..m {
-                res.data[i][j] = T::rand_range(min, max);
+        let mut res = Self::zero(self.n, rhs.m);
+        if res.data.is_empty() || self.m == 0 {
+            return Ok(res);
+        }
+
+        // Cut every row of the result into blocks of `DOT_NC` columns and group the blocks
+        // of `DOT_MC` consecutive rows into tiles, which can then be computed independently.
+        let col_blocks = rhs.m.div_ceil(DOT_NC);
+        let mut tiles = (0..self.n.div_ceil(DOT_MC) * col_blocks)
+            .map(|_| Vec::with_capacity(DOT_MC))
+            .collect::<Vec<Vec<&mut [T]>>>();
+        for (i, row) in res.data.chunks_mut(rhs.m).enumerate() {
+            for (jb, block) in row.chunks_mut(DOT_NC).enumerate() {
+                tiles[(i / DOT_MC) * col_blocks + jb].push(block);
             }
         }
 
-        res
+        tiles.into_par_iter().enumerate().for_each(|(t, mut tile)| {
+            let i0 = (t / col_blocks) * DOT_MC;
+            let j0 = (t % col_blocks) * DOT_NC;
+            let width = tile[0].len();
+            let mut packed = vec![T::ZERO; DOT_KC * width];
+
+            for k0 in (0..self.m).step_by(DOT_KC) {
+                let depth = DOT_KC.min(self.m - k0);
+
+                // Pack the `depth`x`width` panel of `rhs` into a contiguous buffer
+                // which stays in cache while it is reused by every row of the tile.
+                for (k, packed_row) in packed.chunks_exact_mut(width).take(depth).enumerate() {
+                    packed_row.copy_from_slice(&rhs.row(k0 + k)[j0..j0 + width]);
+                }
+
+                for (di, res_row) in tile.iter_mut().enumerate() {
+                    let lhs_row = &self.row(i0 + di)[k0..k0 + depth];
+                    Self::dot_kernel(res_row, lhs_row, &packed[..depth * width]);
+                }
+            }
+        });
+
+        Ok(res)
     }
 
-    pub fn dot(&self, rhs: &Self) -> Self {
-        Matrix::from(
-            (0..self.n)
-                .into_par_iter()
-                .map(|i| {
-                    (0..rhs.m)
-                        .map(|j| (0..self.m).map(|k| self.data[i][k] * rhs.data[k][j]).sum())
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        )
+    /// Adds `lhs_row * packed` to `res_row`, where `packed` holds `lhs_row.len()` rows
+    /// of the width of `res_row`. The inner loops run over contiguous slices of equal
+    /// length so that they get auto-vectorised.
+    #[inline]
+    fn dot_kernel(res_row: &mut [T], lhs_row: &[T], packed: &[T]) {
+        let width = res_row.len();
+
+        // Four rows of the panel at a time, so that `res_row` is loaded and stored once per four updates.
+        let mut lhs_quads = lhs_row.chunks_exact(4);
+        let mut packed_quads = packed.chunks_exact(4 * width);
+        for (a, b) in (&mut lhs_quads).zip(&mut packed_quads) {
+            let (b0, b) = b.split_at(width);
+            let (b1, b) = b.split_at(width);
+            let (b2, b3) = b.split_at(width);
+            for ((((r, &x0), &x1), &x2), &x3) in res_row.iter_mut().zip(b0).zip(b1).zip(b2).zip(b3) {
+                *r = *r + a[0] * x0 + a[1] * x1 + a[2] * x2 + a[3] * x3;
+            }
+        }
+
+        for (&a, b) in lhs_quads.remainder().iter().zip(packed_quads.remainder().chunks_exact(width)) {
+            for (r, &x) in res_row.iter_mut().zip(b) {
+                *r = *r + a * x;
+            }
+        }
     }
 
     pub fn scale(mut self, amt: T) -> Self {
@@ -144,10 +291,8 @@ where
         self
     }
 
-    pub fn hadamard(self, rhs: Self) -> Self {
-        if self.m != rhs.m || self.n != rhs.n {
-            panic!("incompatible dimensions")
-        }
+    pub fn hadamard(self, rhs: Self) -> Result<Self> {
+        self.check_same_shape(&rhs)?;
 
         let mut res = Matrix::zero(self.n, self.m);
 
@@ -157,7 +302,7 @@ where
             }
         }
 
-        res
+        Ok(res)
     }
 
     pub fn tril(&self) -> Self {
@@ -165,9 +310,7 @@ where
         for i in 0..self.n {
             for j in 0..self.m {
                 if j <= i {
-                    res.data[i][j] = self[i][j];
-                } else {
-                    res.data[i][j] = T::ZERO;
+                    res[i][j] = self[i][j];
                 }
             }
         }
@@ -179,20 +322,34 @@ where
         for i in 0..self.n {
             for j in 0..self.m {
                 if j >= i {
-                    res.data[i][j] = self[i][j];
-                } else {
-                    res.data[i][j] = T::ZERO;
+                    res[i][j] = self[i][j];
                 }
             }
         }
         res
     }
 
+    /// The `len`x`len` block on the diagonal whose first row (and column) is `start`.
+    pub fn diagonal_block(&self, start: usize, len: usize) -> Self {
+        let mut res = Self::zero(len, len);
+        for i in 0..len {
+            res[i].copy_from_slice(&self[start + i][start..start + len]);
+        }
+        res
+    }
+
     pub fn transpose(&self) -> Self {
+        // Walk the matrix in square blocks so that neither side is strided across the whole buffer.
+        const BLOCK: usize = 32;
+
         let mut res = Matrix::zero(self.m, self.n);
-        for i in 0..self.n {
-            for j in 0..self.m {
-                res.data[j][i] = self.data[i][j];
+        for ib in (0..self.n).step_by(BLOCK) {
+            for jb in (0..self.m).step_by(BLOCK) {
+                for i in ib..(ib + BLOCK).min(self.n) {
+                    for j in jb..(jb + BLOCK).min(self.m) {
+                        res.data[j * self.n + i] = self.data[i * self.m + j];
+                    }
+                }
             }
         }
         res
@@ -203,12 +360,10 @@ impl<T> Add for Matrix<T>
 where
     T: MatrixElement,
 {
-    type Output = Self;
+    type Output = Result<Self>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        if self.m != rhs.m || self.n != rhs.n {
-            panic!("incompatible dimensions")
-        }
+        self.check_same_shape(&rhs)?;
 
         let mut res = Matrix::zero(self.n, self.m);
         for i in 0..self.n {
@@ -216,7 +371,7 @@ where
                 res[i][j] = self[i][j] + rhs[i][j];
             }
         }
-        res
+        Ok(res)
     }
 }
 
@@ -224,12 +379,10 @@ impl<T> Sub for Matrix<T>
 where
     T: MatrixElement,
 {
-    type Output = Self;
+    type Output = Result<Self>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        if self.m != rhs.m || self.n != rhs.n {
-            panic!("incompatible dimensions")
-        }
+        self.check_same_shape(&rhs)?;
 
         let mut res = Matrix::zero(self.n, self.m);
         for i in 0..self.n {
@@ -237,18 +390,19 @@ where
                 res[i][j] = self[i][j] - rhs[i][j];
             }
         }
-        res
+        Ok(res)
     }
 }
 
+/// The `i`-th row, see `Matrix::row`.
 impl<T> Index<usize> for Matrix<T>
 where
     T: MatrixElement,
 {
-    type Output = Vec<T>;
+    type Output = [T];
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.data[index]
+        self.row(index)
     }
 }
 
@@ -257,7 +411,33 @@ where
     T: MatrixElement,
 {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.data[index]
+        self.row_mut(index)
+    }
+}
+
+/// The element in the `i`-th row and the `j`-th column.
+///
+/// # Panics
+/// If `i` or `j` is out of bounds.
+impl<T> Index<(usize, usize)> for Matrix<T>
+where
+    T: MatrixElement,
+{
+    type Output = T;
+
+    fn index(&self, (i, j): (usize, usize)) -> &Self::Output {
+        assert!(j < self.m, "column index out of bounds");
+        &self.data[i * self.m + j]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Matrix<T>
+where
+    T: MatrixElement,
+{
+    fn index_mut(&mut self, (i, j): (usize, usize)) -> &mut Self::Output {
+        assert!(j < self.m, "column index out of bounds");
+        &mut self.data[i * self.m + j]
     }
 }
 
@@ -267,16 +447,10 @@ where
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let elts = self
-            .data
-            .iter()
+            .rows()
             .map(|row| row.iter().map(|elt| format!("{}", elt)).collect::<Vec<_>>())
             .collect::<Vec<_>>();
-        let mut max_len = elts[0][0].len();
-        for i in 0..self.n {
-            for j in 0..self.m {
-                max_len = max_len.max(elts[i][j].len());
-            }
-        }
+        let max_len = elts.iter().flatten().map(|elt| elt.len()).max().unwrap_or(0);
         write!(
             f,
             "{}",
@@ -298,80 +472,1100 @@ impl<T> Matrix<T>
 where
     T: MatrixElement,
 {
-    fn back_substitution_u(mat: &Matrix<T>, b: Vec<T>) -> Vec<T> {
+    /// Returns an error unless `mat` is square and matches the length of `b`.
+    fn check_shape(mat: &Matrix<T>, b: &[T]) -> Result<()> {
+        if mat.n != mat.m || mat.n != b.len() {
+            return Err(Error::DimensionMismatch {
+                expected: (b.len(), b.len()),
+                found: (mat.n, mat.m),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns an error unless `mat` is square, matches the length of `b` and has no zero on its diagonal.
+    fn check_system(mat: &Matrix<T>, b: &[T]) -> Result<()> {
+        Matrix::check_shape(mat, b)?;
+        if (0..mat.n).any(|i| mat[i][i] == T::ZERO) {
+            return Err(Error::SingularKey);
+        }
+        Ok(())
+    }
+
+    fn back_substitution_u(mat: &Matrix<T>, b: Vec<T>) -> Result<Vec<T>> {
+        Matrix::check_system(mat, &b)?;
         let mut x = vec![T::ZERO; mat.n];
 
         for i in (0..mat.n).rev() {
             x[i] = (b[i] - (i + 1..mat.n).map(|j| x[j] * mat[i][j]).sum()) / mat[i][i];
         }
 
-        x
+        Ok(x)
     }
 
-    fn forward_substitution_l(mat: &Matrix<T>, b: Vec<T>) -> Vec<T> {
+    fn forward_substitution_l(mat: &Matrix<T>, b: Vec<T>) -> Result<Vec<T>> {
+        Matrix::check_system(mat, &b)?;
         let mut x = vec![T::ZERO; mat.n];
 
         for i in 0..mat.n {
             x[i] = (b[i] - (0..i).map(|j| x[j] * mat[i][j]).sum()) / mat[i][i];
         }
 
-        x
+        Ok(x)
+    }
+
+    pub fn solve_system_lu(l: &Matrix<T>, u: &Matrix<T>, b: Vec<T>) -> Result<Vec<T>> {
+        Matrix::back_substitution_u(u, Matrix::forward_substitution_l(l, b)?)
+    }
+
+    /// Solves `LUX = B` for all the columns of `b` at once, replacing them with the solutions.
+    /// The columns are cut into blocks of `TRSM_NC`, each packed into a contiguous buffer and
+    /// solved independently, a row of the block at a time.
+    pub fn solve_system_lu_in_place(l: &Matrix<T>, u: &Matrix<T>, b: &mut Matrix<T>) -> Result<()> {
+        let zero = vec![T::ZERO; b.n];
+        Matrix::check_system(l, &zero)?;
+        Matrix::check_system(u, &zero)?;
+        if b.data.is_empty() {
+            return Ok(());
+        }
+
+        // The blocks of every row, grouped by block of columns.
+        let col_blocks = b.m.div_ceil(TRSM_NC);
+        let mut blocks = (0..col_blocks).map(|_| Vec::with_capacity(b.n)).collect::<Vec<Vec<&mut [T]>>>();
+        for row in b.data.chunks_mut(b.m) {
+            for (jb, block) in row.chunks_mut(TRSM_NC).enumerate() {
+                blocks[jb].push(block);
+            }
+        }
+
+        blocks.into_par_iter().for_each(|mut rows| {
+            let width = rows[0].len();
+            let mut packed = rows.iter().flat_map(|row| row.iter().copied()).collect::<Vec<_>>();
+            Matrix::forward_substitution_block(l, &mut packed, width);
+            Matrix::back_substitution_block(u, &mut packed, width);
+            for (row, solved) in rows.iter_mut().zip(packed.chunks_exact(width)) {
+                row.copy_from_slice(solved);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Forward substitution of the `width` columns of `packed` (row-major): every row minus the
+    /// rows above it, already solved.
+    fn forward_substitution_block(l: &Matrix<T>, packed: &mut [T], width: usize) {
+        let mut sum = vec![T::ZERO; width];
+        for i in 0..l.n {
+            let (solved, rest) = packed.split_at_mut(i * width);
+            sum.fill(T::ZERO);
+            Self::dot_kernel(&mut sum, &l.row(i)[..i], solved);
+            for (x, &sum) in rest[..width].iter_mut().zip(&sum) {
+                *x = (*x - sum) / l[i][i];
+            }
+        }
+    }
+
+    /// Back substitution of the `width` columns of `packed` (row-major): every row minus the
+    /// rows below it, already solved.
+    fn back_substitution_block(u: &Matrix<T>, packed: &mut [T], width: usize) {
+        let mut sum = vec![T::ZERO; width];
+        for i in (0..u.n).rev() {
+            let (row, solved) = packed[i * width..].split_at_mut(width);
+            sum.fill(T::ZERO);
+            Self::dot_kernel(&mut sum, &u.row(i)[i + 1..], solved);
+            for (x, &sum) in row.iter_mut().zip(&sum) {
+                *x = (*x - sum) / u[i][i];
+            }
+        }
+    }
+}
+
+/// The number of iterations `Matrix::jacobi_spectral_radius` does.
+const POWER_ITERATIONS: usize = 200;
+
+/// ## Tolerance
+/// When an iterative method stops: as soon as the residual `||b - Ax||` is at most `absolute`
+/// or `relative * ||b||`, and after `max_iterations` iterations at the latest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub absolute: f64,
+    pub relative: f64,
+    pub max_iterations: usize,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            absolute: 0.0,
+            relative: 1e-6,
+            max_iterations: 100,
+        }
+    }
+}
+
+/// How the iterative solution of a single system ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Convergence {
+    /// The number of iterations done.
+    pub iterations: usize,
+    /// The final residual `||b - Ax||`.
+    pub residual: f64,
+    /// Whether the residual got within the tolerance.
+    pub converged: bool,
+}
+
+/// ## Solver
+/// An iterative method for the systems `Ax = b`, which starts from `x = 0` and stops as soon as
+/// the residual is within the tolerance. The work is done in `f64`.
+pub trait Solver<T: FloatElement>: Send + Sync {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)>;
+}
+
+/// ## Jacobi
+/// Every element of the solution from the others of the previous iteration.
+#[derive(Debug, Clone, Copy)]
+pub struct Jacobi;
+
+impl<T: FloatElement> Solver<T> for Jacobi {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_system(a, b)?;
+        let b = to_f64(b);
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            let next = (0..a.n).map(|i| (b[i] - a.off_diagonal(i, x)) / a[i][i].to_f64()).collect::<Vec<_>>();
+            x.copy_from_slice(&next);
+            true
+        }))
+    }
+}
+
+/// ## Gauss-Seidel
+/// Every element of the solution from the others, the ones before it already updated.
+#[derive(Debug, Clone, Copy)]
+pub struct GaussSeidel;
+
+impl<T: FloatElement> Solver<T> for GaussSeidel {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Sor { omega: 1.0 }.solve(a, b, tolerance)
+    }
+}
+
+/// ## SOR
+/// Successive Over-Relaxation: Gauss-Seidel, with every step taken `omega` times.
+#[derive(Debug, Clone, Copy)]
+pub struct Sor {
+    pub omega: f64,
+}
+
+impl<T: FloatElement> Solver<T> for Sor {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_system(a, b)?;
+        let b = to_f64(b);
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            a.sor_sweep(&b, x, self.omega, 0..a.n);
+            true
+        }))
+    }
+}
+
+/// ## SSOR
+/// Symmetric SOR: a sweep of SOR over the rows, followed by one in the reverse order.
+#[derive(Debug, Clone, Copy)]
+pub struct Ssor {
+    pub omega: f64,
+}
+
+impl<T: FloatElement> Solver<T> for Ssor {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_system(a, b)?;
+        let b = to_f64(b);
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            a.sor_sweep(&b, x, self.omega, 0..a.n);
+            a.sor_sweep(&b, x, self.omega, (0..a.n).rev());
+            true
+        }))
+    }
+}
+
+/// ## CG
+/// Conjugate Gradient on the normal equations `A^T A x = A^T b` (CGNR), since `A` is neither
+/// symmetric nor positive definite in general. With a preconditioner `M`, on the ones of
+/// `A M^-1 y = b`, `x = M^-1 y`.
+#[derive(Default)]
+pub struct ConjugateGradient {
+    pub preconditioner: Option<Box<dyn Preconditioner>>,
+}
+
+impl<T: FloatElement> Solver<T> for ConjugateGradient {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_shape(a, b)?;
+        let preconditioner = self.preconditioner.as_deref();
+        let b = to_f64(b);
+        // The residual of `Ax = b`, the one of the normal equations and the search direction.
+        let mut r = b.clone();
+        let mut z = precondition_transposed(preconditioner, a.mul_vec_transposed(&r));
+        let mut p = z.clone();
+        let mut zz = dot(&z, &z);
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            let q = precondition(preconditioner, p.clone());
+            let w = a.mul_vec(&q);
+            let ww = dot(&w, &w);
+            if zz == 0.0 || ww == 0.0 {
+                return false;
+            }
+            let alpha = zz / ww;
+            axpy(alpha, &q, x);
+            axpy(-alpha, &w, &mut r);
+
+            z = precondition_transposed(preconditioner, a.mul_vec_transposed(&r));
+            let next = dot(&z, &z);
+            let beta = next / zz;
+            zz = next;
+            for (p, &z) in p.iter_mut().zip(&z) {
+                *p = z + beta * *p;
+            }
+            true
+        }))
+    }
+}
+
+/// ## GMRES
+/// Generalized Minimal RESidual, restarted after every `restart` iterations (`GMRES(m)`): the
+/// solution which minimizes the residual over the Krylov space of the residual of the restart.
+/// With a preconditioner `M`, the space is the one of `A M^-1`.
+#[derive(Default)]
+pub struct Gmres {
+    pub restart: usize,
+    pub preconditioner: Option<Box<dyn Preconditioner>>,
+}
+
+impl<T: FloatElement> Solver<T> for Gmres {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_shape(a, b)?;
+        let b = to_f64(b);
+        let restart = self.restart.max(1);
+        let preconditioner = self.preconditioner.as_deref();
+        let mut cycle: Option<GmresCycle> = None;
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            if cycle.as_ref().is_none_or(|cycle| cycle.len() == restart || !cycle.can_grow()) {
+                let next = GmresCycle::new(a, &b, x.to_vec());
+                if !next.can_grow() {
+                    return false;
+                }
+                cycle = Some(next);
+            }
+
+            let cycle = cycle.as_mut().unwrap();
+            cycle.grow(a, preconditioner);
+            match cycle.solution(preconditioner) {
+                Some(solution) => {
+                    x.copy_from_slice(&solution);
+                    true
+                }
+                None => false,
+            }
+        }))
+    }
+}
+
+/// A cycle of `Gmres`: the orthonormal basis of the Krylov space of the residual of `x0`, and
+/// the Hessenberg matrix of `A` in it, turned upper triangular by Givens rotations as it grows.
+struct GmresCycle {
+    x0: Vec<f64>,
+    basis: Vec<Vec<f64>>,
+    /// The columns of the triangular matrix.
+    columns: Vec<Vec<f64>>,
+    /// The cosine and the sine of every rotation.
+    rotations: Vec<(f64, f64)>,
+    /// The rotated `||r0|| e1`, whose last element is the residual of the current solution.
+    g: Vec<f64>,
+}
+
+impl GmresCycle {
+    fn new<T: FloatElement>(a: &Matrix<T>, b: &[f64], x0: Vec<f64>) -> Self {
+        let r = b.iter().zip(a.mul_vec(&x0)).map(|(b, ax)| b - ax).collect::<Vec<_>>();
+        let beta = norm(&r);
+        let basis = if beta > 0.0 && beta.is_finite() { vec![r.iter().map(|r| r / beta).collect()] } else { Vec::new() };
+        GmresCycle { x0, basis, columns: Vec::new(), rotations: Vec::new(), g: vec![beta] }
+    }
+
+    /// The number of iterations of the cycle.
+    fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// Whether there is a basis vector left to grow the space with (there is none once the
+    /// space holds the exact solution).
+    fn can_grow(&self) -> bool {
+        self.basis.len() > self.columns.len()
+    }
+
+    /// Adds `A M^-1` times the last basis vector to the space (Arnoldi with modified Gram-Schmidt).
+    fn grow<T: FloatElement>(&mut self, a: &Matrix<T>, preconditioner: Option<&dyn Preconditioner>) {
+        let j = self.columns.len();
+        let mut w = a.mul_vec(&precondition(preconditioner, self.basis[j].clone()));
+        let mut h = Vec::with_capacity(j + 2);
+        for v in &self.basis {
+            let hij = dot(&w, v);
+            axpy(-hij, v, &mut w);
+            h.push(hij);
+        }
+        let next = norm(&w);
+        h.push(next);
+
+        for (i, &(c, s)) in self.rotations.iter().enumerate() {
+            (h[i], h[i + 1]) = (c * h[i] + s * h[i + 1], c * h[i + 1] - s * h[i]);
+        }
+        let d = h[j].hypot(h[j + 1]);
+        let (c, s) = if d == 0.0 { (1.0, 0.0) } else { (h[j] / d, h[j + 1] / d) };
+        self.rotations.push((c, s));
+        h[j] = d;
+        h.truncate(j + 1);
+        self.columns.push(h);
+        let gj = self.g[j];
+        self.g[j] = c * gj;
+        self.g.push(-s * gj);
+
+        if next > 0.0 {
+            self.basis.push(w.iter().map(|w| w / next).collect());
+        }
     }
 
-    pub fn solve_system_lu(l: &Matrix<T>, u: &Matrix<T>, b: Vec<T>) -> Vec<T> {
-        Matrix::back_substitution_u(u, Matrix::forward_substitution_l(l, b))
+    /// The solution with the smallest residual in the space, `None` if the triangular matrix is
+    /// singular.
+    fn solution(&self, preconditioner: Option<&dyn Preconditioner>) -> Option<Vec<f64>> {
+        let k = self.columns.len();
+        let mut y = vec![0.0; k];
+        for i in (0..k).rev() {
+            if self.columns[i][i] == 0.0 {
+                return None;
+            }
+            let sum = (i + 1..k).map(|j| self.columns[j][i] * y[j]).sum::<f64>();
+            y[i] = (self.g[i] - sum) / self.columns[i][i];
+        }
+
+        let mut step = vec![0.0; self.x0.len()];
+        for (y, v) in y.iter().zip(&self.basis) {
+            axpy(*y, v, &mut step);
+        }
+        let mut x = self.x0.clone();
+        axpy(1.0, &precondition(preconditioner, step), &mut x);
+        Some(x)
+    }
+}
+
+/// ## BiCGSTAB
+/// Biconjugate Gradient Stabilized, for nonsymmetric `A`, preconditioned on the right.
+#[derive(Default)]
+pub struct BiCgStab {
+    pub preconditioner: Option<Box<dyn Preconditioner>>,
+}
+
+impl<T: FloatElement> Solver<T> for BiCgStab {
+    fn solve(&self, a: &Matrix<T>, b: &[T], tolerance: &Tolerance) -> Result<(Vec<T>, Convergence)> {
+        Matrix::check_shape(a, b)?;
+        let preconditioner = self.preconditioner.as_deref();
+        let b = to_f64(b);
+        let mut r = b.clone();
+        let r_hat = b.clone();
+        let (mut rho, mut alpha, mut omega) = (1.0, 1.0, 1.0);
+        let mut v = vec![0.0; a.n];
+        let mut p = vec![0.0; a.n];
+
+        Ok(iterate(a, &b, tolerance, |x| {
+            let rho_next = dot(&r_hat, &r);
+            if rho_next == 0.0 || omega == 0.0 {
+                return false;
+            }
+            let beta = (rho_next / rho) * (alpha / omega);
+            rho = rho_next;
+            for ((p, &r), &v) in p.iter_mut().zip(&r).zip(&v) {
+                *p = r + beta * (*p - omega * v);
+            }
+
+            let p_hat = precondition(preconditioner, p.clone());
+            v = a.mul_vec(&p_hat);
+            let r_hat_v = dot(&r_hat, &v);
+            if r_hat_v == 0.0 {
+                return false;
+            }
+            alpha = rho / r_hat_v;
+            let s = r.iter().zip(&v).map(|(r, v)| r - alpha * v).collect::<Vec<_>>();
+            let s_hat = precondition(preconditioner, s.clone());
+            let t = a.mul_vec(&s_hat);
+            let tt = dot(&t, &t);
+            omega = if tt == 0.0 { 0.0 } else { dot(&t, &s) / tt };
+
+            axpy(alpha, &p_hat, x);
+            axpy(omega, &s_hat, x);
+            r = s.iter().zip(&t).map(|(s, t)| s - omega * t).collect();
+            true
+        }))
+    }
+}
+
+/// ## Preconditioner
+/// An approximation `M` of the system matrix whose systems are cheap to solve. The Krylov
+/// solvers solve `A M^-1 y = b` instead of `Ax = b`, which takes fewer iterations the closer `M`
+/// is to `A`, then `x = M^-1 y`.
+pub trait Preconditioner: Send + Sync {
+    /// `M^-1 r`
+    fn apply(&self, r: &[f64]) -> Vec<f64>;
+
+    /// `M^-T r`
+    fn apply_transposed(&self, r: &[f64]) -> Vec<f64>;
+}
+
+/// ## Jacobi preconditioner
+/// The diagonal of the system matrix.
+pub struct JacobiPreconditioner {
+    diagonal: Vec<f64>,
+}
+
+impl JacobiPreconditioner {
+    pub fn new<T: FloatElement>(a: &Matrix<T>) -> Result<Self> {
+        Matrix::check_system(a, &vec![T::ZERO; a.n])?;
+        Ok(JacobiPreconditioner { diagonal: (0..a.n).map(|i| a[i][i].to_f64()).collect() })
+    }
+}
+
+impl Preconditioner for JacobiPreconditioner {
+    fn apply(&self, r: &[f64]) -> Vec<f64> {
+        r.iter().zip(&self.diagonal).map(|(r, d)| r / d).collect()
+    }
+
+    fn apply_transposed(&self, r: &[f64]) -> Vec<f64> {
+        self.apply(r)
+    }
+}
+
+/// ## LU preconditioner
+/// A product `M = LU` of a lower and an upper triangular matrix, whose systems are solved by
+/// forward and back substitution.
+pub struct LuPreconditioner {
+    lower: Matrix<f64>,
+    upper: Matrix<f64>,
+}
+
+impl LuPreconditioner {
+    /// The given factors, e.g. the `tril` and the `triu` of the key, for which `M` is the system
+    /// matrix itself and any method converges in a single iteration.
+    pub fn from_factors<T: FloatElement>(l: &Matrix<T>, u: &Matrix<T>) -> Result<Self> {
+        LuPreconditioner::new(l.map(|x| x.to_f64()), u.map(|x| x.to_f64()))
+    }
+
+    /// The incomplete LU factorization ILU(0): Gaussian elimination which only updates the
+    /// elements which are not zero in `A`, so `L + U` has the sparsity of `A`.
+    pub fn ilu0<T: FloatElement>(a: &Matrix<T>) -> Result<Self> {
+        Matrix::check_system(a, &vec![T::ZERO; a.n])?;
+        let n = a.n;
+        let mut lu = a.map(|x| x.to_f64());
+
+        for i in 1..n {
+            for k in (0..i).filter(|&k| a[i][k] != T::ZERO) {
+                if lu[k][k] == 0.0 {
+                    return Err(Error::SingularKey);
+                }
+                let factor = lu[i][k] / lu[k][k];
+                lu[i][k] = factor;
+                for j in (k + 1..n).filter(|&j| a[i][j] != T::ZERO) {
+                    lu[i][j] -= factor * lu[k][j];
+                }
+            }
+        }
+
+        let mut lower = lu.tril();
+        for i in 0..n {
+            lower[i][i] = 1.0;
+        }
+        LuPreconditioner::new(lower, lu.triu())
+    }
+
+    /// The SSOR preconditioner `(D + omega E) D^-1 (D + omega F) / (omega (2 - omega))`, where
+    /// `D`, `E` and `F` are the diagonal, the strictly lower and the strictly upper part of `A`.
+    pub fn ssor<T: FloatElement>(a: &Matrix<T>, omega: f64) -> Result<Self> {
+        Matrix::check_system(a, &vec![T::ZERO; a.n])?;
+        let n = a.n;
+        let mut lower = Matrix::zero(n, n);
+        let mut upper = Matrix::zero(n, n);
+
+        for i in 0..n {
+            let d = a[i][i].to_f64();
+            lower[i][i] = 1.0;
+            upper[i][i] = d / (omega * (2.0 - omega));
+            for j in 0..i {
+                lower[i][j] = omega * a[i][j].to_f64() / a[j][j].to_f64();
+            }
+            for j in i + 1..n {
+                upper[i][j] = a[i][j].to_f64() / (2.0 - omega);
+            }
+        }
+        LuPreconditioner::new(lower, upper)
+    }
+
+    fn new(lower: Matrix<f64>, upper: Matrix<f64>) -> Result<Self> {
+        let zero = vec![0.0; lower.n];
+        Matrix::check_system(&lower, &zero)?;
+        Matrix::check_system(&upper, &zero)?;
+        Ok(LuPreconditioner { lower, upper })
+    }
+}
+
+impl Preconditioner for LuPreconditioner {
+    fn apply(&self, r: &[f64]) -> Vec<f64> {
+        // The factors were checked when built.
+        Matrix::solve_system_lu(&self.lower, &self.upper, r.to_vec()).unwrap()
+    }
+
+    fn apply_transposed(&self, r: &[f64]) -> Vec<f64> {
+        // `M^T = U^T L^T`: `U^T` is lower triangular and `L^T` upper triangular, both solved
+        // a row of the factor at a time.
+        let n = self.upper.n;
+        let mut w = r.to_vec();
+        for i in 0..n {
+            w[i] /= self.upper[i][i];
+            for j in i + 1..n {
+                w[j] -= self.upper[i][j] * w[i];
+            }
+        }
+        for i in (0..n).rev() {
+            w[i] /= self.lower[i][i];
+            for j in 0..i {
+                w[j] -= self.lower[i][j] * w[i];
+            }
+        }
+        w
+    }
+}
+
+/// `M^-1 x`, `x` itself without a preconditioner.
+fn precondition(preconditioner: Option<&dyn Preconditioner>, x: Vec<f64>) -> Vec<f64> {
+    match preconditioner {
+        Some(preconditioner) => preconditioner.apply(&x),
+        None => x,
+    }
+}
+
+/// `M^-T x`, `x` itself without a preconditioner.
+fn precondition_transposed(preconditioner: Option<&dyn Preconditioner>, x: Vec<f64>) -> Vec<f64> {
+    match preconditioner {
+        Some(preconditioner) => preconditioner.apply_transposed(&x),
+        None => x,
+    }
+}
+
+/// Calls `step` on the solution, starting from zero, until its residual is within the tolerance
+/// or `step` can not go on (a breakdown of the method).
+fn iterate<T: FloatElement>(
+    a: &Matrix<T>,
+    b: &[f64],
+    tolerance: &Tolerance,
+    mut step: impl FnMut(&mut [f64]) -> bool,
+) -> (Vec<T>, Convergence) {
+    let mut x = vec![0.0; b.len()];
+    let mut residual = norm(b);
+    let target = tolerance.absolute.max(tolerance.relative * residual);
+
+    let mut iterations = 0;
+    // A diverging solution never gets back.
+    while residual > target && residual.is_finite() && iterations < tolerance.max_iterations {
+        let went_on = step(&mut x);
+        iterations += 1;
+        residual = a.residual_norm(b, &x);
+        if !went_on {
+            break;
+        }
+    }
+
+    let x = x.into_iter().map(T::from_f64).collect();
+    (x, Convergence { iterations, residual, converged: residual <= target })
+}
+
+fn to_f64<T: FloatElement>(x: &[T]) -> Vec<f64> {
+    x.iter().map(|x| x.to_f64()).collect()
+}
+
+fn dot(x: &[f64], y: &[f64]) -> f64 {
+    x.iter().zip(y).map(|(x, y)| x * y).sum()
+}
+
+fn norm(x: &[f64]) -> f64 {
+    dot(x, x).sqrt()
+}
+
+/// `y += alpha x`
+fn axpy(alpha: f64, x: &[f64], y: &mut [f64]) {
+    for (y, x) in y.iter_mut().zip(x) {
+        *y += alpha * x;
     }
 }
 
 impl<T> Matrix<T>
 where
-    T: MatrixElement,
+    T: FloatElement,
 {
     /// ## SOR
-    /// Successive Over-Relaxation
-    pub fn solve_system_iterative(a: &Matrix<T>, b: Vec<T>, omega: T, iterations: usize) -> Vec<T> {
-        let mut x = vec![T::ZERO; b.len()];
+    /// Successive Over-Relaxation, until the residual is within the tolerance, see `Sor`.
+    pub fn solve_system_iterative(
+        a: &Matrix<T>,
+        b: Vec<T>,
+        omega: T,
+        tolerance: &Tolerance,
+    ) -> Result<(Vec<T>, Convergence)> {
+        Sor { omega: omega.to_f64() }.solve(a, &b, tolerance)
+    }
 
-        for _ in 0..iterations {
-            for i in 0..a.n {
-                let sum = (0..a.n).filter(|&j| j != i).map(|j| a[i][j] * x[j]).sum();
-                x[i] = omega / a[i][i] * (b[i] - sum) + (T::ONE - omega) * x[i];
+    /// The spectral radius of the Jacobi iteration matrix `I - D^-1 A` (`D` is the diagonal of
+    /// `A`), estimated with the power iteration.
+    pub fn jacobi_spectral_radius(&self) -> Result<f64> {
+        Matrix::check_system(self, &vec![T::ZERO; self.n])?;
+        if self.n == 0 {
+            return Ok(0.0);
+        }
+
+        let mut v = (0..self.n).map(|i| 1.0 + i as f64 / self.n as f64).collect::<Vec<_>>();
+        let mut logs = Vec::with_capacity(POWER_ITERATIONS);
+        for _ in 0..POWER_ITERATIONS {
+            let w = (0..self.n).map(|i| -self.off_diagonal(i, &v) / self[i][i].to_f64()).collect::<Vec<_>>();
+            let norm = norm(&w);
+            if norm == 0.0 || !norm.is_finite() {
+                return Ok(norm);
             }
+            logs.push(norm.ln());
+            v = w.into_iter().map(|x| x / norm).collect();
+        }
+
+        // The mean growth of the last iterations, which also averages out complex eigenvalues.
+        let last = &logs[POWER_ITERATIONS / 2..];
+        Ok((last.iter().sum::<f64>() / last.len() as f64).exp())
+    }
+
+    /// `Ax`
+    fn mul_vec(&self, x: &[f64]) -> Vec<f64> {
+        self.rows()
+            .map(|row| row.iter().zip(x).map(|(&a, &x)| a.to_f64() * x).sum())
+            .collect()
+    }
+
+    /// `A^T x`
+    fn mul_vec_transposed(&self, x: &[f64]) -> Vec<f64> {
+        let mut res = vec![0.0; self.m];
+        for (row, &x) in self.rows().zip(x) {
+            for (res, &a) in res.iter_mut().zip(row) {
+                *res += a.to_f64() * x;
+            }
+        }
+        res
+    }
+
+    /// The `i`-th row of `Ax`, without the diagonal element.
+    fn off_diagonal(&self, i: usize, x: &[f64]) -> f64 {
+        self.row(i)
+            .iter()
+            .zip(x)
+            .enumerate()
+            .filter(|&(j, _)| j != i)
+            .map(|(_, (&a, &x))| a.to_f64() * x)
+            .sum()
+    }
+
+    /// A sweep of SOR over the rows in the given order.
+    fn sor_sweep(&self, b: &[f64], x: &mut [f64], omega: f64, rows: impl Iterator<Item = usize>) {
+        for i in rows {
+            let gauss_seidel = (b[i] - self.off_diagonal(i, x)) / self[i][i].to_f64();
+            x[i] = omega * gauss_seidel + (1.0 - omega) * x[i];
         }
+    }
 
-        x
+    /// The euclidean norm of `b - Ax`.
+    fn residual_norm(&self, b: &[f64], x: &[f64]) -> f64 {
+        let ax = self.mul_vec(x);
+        b.iter().zip(ax).map(|(b, ax)| (b - ax) * (b - ax)).sum::<f64>().sqrt()
     }
 }
 
 // Float matrix
 
-use rand::Rng;
+/// ## Float element
+/// A floating point `MatrixElement` whose binary representation can be stored in the ciphertext.
+pub trait FloatElement: MatrixElement {
+    /// The number of bits of the binary representation.
+    const BIT_WIDTH: usize;
+
+    /// Returns the binary representation, zero-extended to 64 bits.
+    fn to_bits_u64(self) -> u64;
+
+    /// Restores a value from the lower `Self::BIT_WIDTH` bits of `bits`.
+    fn from_bits_u64(bits: u64) -> Self;
+
+    fn from_f64(x: f64) -> Self;
+
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! impl_float_element {
+    ($t:ty, $bits:ty) => {
+        impl RandomRange for $t {
+            fn rand_range<R: Rng + ?Sized>(rng: &mut R, min: &Self, max: &Self) -> Self {
+                rng.gen_range(*min..=*max)
+            }
+        }
 
-impl RandomRange for f32 {
-    fn rand_range(min: &Self, max: &Self) -> Self {
-        let mut rng = rand::thread_rng();
-        rng.gen_range(*min..=*max)
+        impl Field for $t {
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+
+            fn inverse(&self) -> Option<Self> {
+                (*self != 0.0).then(|| 1.0 / self)
+            }
+        }
+
+        impl Exponent for $t {
+            fn dpow(self, e: i32) -> Self {
+                self.powi(e)
+            }
+        }
+
+        impl Round for $t {
+            fn dround(&self, decimals: usize) -> Self {
+                let d = (10 as $t).powi(decimals as i32);
+                (self * d).round() / d
+            }
+        }
+
+        impl MatrixElement for $t {}
+
+        impl FloatElement for $t {
+            const BIT_WIDTH: usize = <$bits>::BITS as usize;
+
+            fn to_bits_u64(self) -> u64 {
+                self.to_bits() as u64
+            }
+
+            fn from_bits_u64(bits: u64) -> Self {
+                <$t>::from_bits(bits as $bits)
+            }
+
+            fn from_f64(x: f64) -> Self {
+                x as $t
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+    };
+}
+
+impl_float_element!(f32, u32);
+impl_float_element!(f64, u64);
+
+pub type FloatMatrix = Matrix<f32>;
+pub type DoubleMatrix = Matrix<f64>;
+
+// Modular matrix
+
+/// ## Zp
+/// An element of the prime field `Z_P` (the integers modulo `P`), stored as its
+/// representative in `[0, P)`. `P` must be a prime for `Div` and `Field::inverse` to be sound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Zp<const P: u32>(u32);
+
+impl<const P: u32> Zp<P> {
+    pub const MODULUS: u32 = P;
+
+    /// Returns the element congruent to `value`.
+    pub fn new(value: u64) -> Self {
+        Self((value % P as u64) as u32)
+    }
+
+    /// Returns the representative in `[0, P)`.
+    pub fn value(self) -> u32 {
+        self.0
+    }
+
+    /// Raises the element to a non-negative power by repeated squaring.
+    pub fn pow(self, mut e: u64) -> Self {
+        let mut base = self;
+        let mut res = Self::ONE;
+        while e > 0 {
+            if e & 1 == 1 {
+                res = res * base;
+            }
+            base = base * base;
+            e >>= 1;
+        }
+        res
+    }
+}
+
+impl<const P: u32> Add for Zp<P> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self::new(self.0 as u64 + rhs.0 as u64)
+    }
+}
+
+impl<const P: u32> Sub for Zp<P> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self::new(self.0 as u64 + (P - rhs.0) as u64)
+    }
+}
+
+impl<const P: u32> Mul for Zp<P> {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self::new(self.0 as u64 * rhs.0 as u64)
     }
 }
 
-impl Field for f32 {
-    const ZERO: Self = 0.0;
-    const ONE: Self = 1.0;
+impl<const P: u32> Div for Zp<P> {
+    type Output = Self;
+
+    /// ### Panics:
+    /// - if `rhs` is zero.
+    // Dividing is multiplying by the inverse.
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, rhs: Self) -> Self::Output {
+        self * rhs.inverse().expect("division by zero")
+    }
+}
+
+impl<const P: u32> Sum for Zp<P> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, |acc, x| acc + x)
+    }
+}
+
+impl<const P: u32> Field for Zp<P> {
+    const ZERO: Self = Self(0);
+    const ONE: Self = Self(1);
+
+    /// By Fermat's little theorem `a^(P-1) = 1`, so `a^(P-2)` is the inverse of `a`.
+    fn inverse(&self) -> Option<Self> {
+        (self.0 != 0).then(|| self.pow(P as u64 - 2))
+    }
+}
+
+impl<const P: u32> RandomRange for Zp<P> {
+    fn rand_range<R: Rng + ?Sized>(rng: &mut R, min: &Self, max: &Self) -> Self {
+        Self(rng.gen_range(min.0..=max.0))
+    }
 }
 
-impl Exponent for f32 {
+impl<const P: u32> Exponent for Zp<P> {
     fn dpow(self, e: i32) -> Self {
-        self.powi(e)
+        if e < 0 {
+            self.inverse().expect("division by zero").pow(e.unsigned_abs() as u64)
+        } else {
+            self.pow(e as u64)
+        }
     }
 }
 
-impl Round for f32 {
-    fn dround(&self, decimals: usize) -> Self {
-        let d = 10f32.powi(decimals as i32);
-        (self * d as f32).round() / d as f32
+impl<const P: u32> Round for Zp<P> {
+    /// The elements are integers already.
+    fn dround(&self, _decimals: usize) -> Self {
+        *self
     }
 }
 
-impl MatrixElement for f32 {}
+impl<const P: u32> Display for Zp<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-pub type FloatMatrix = Matrix<f32>;
+impl<const P: u32> MatrixElement for Zp<P> {}
+
+/// The field used by the modular cipher: every byte is an element of it.
+pub type Z257 = Zp<257>;
+pub type ModularMatrix = Matrix<Z257>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::gen_key_with_rng;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    /// A generated key, its system matrix `K = tril(key) triu(key)` and a system `Kx = b` with
+    /// a known solution `x`.
+    fn key_system(n: usize, seed: u64) -> (FloatMatrix, FloatMatrix, Vec<f32>, Vec<f32>) {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let key = gen_key_with_rng(n, false, &mut rng).unwrap();
+        let k = key.tril().dot(&key.triu()).unwrap();
+        let x = (0..n).map(|_| rng.gen_range(0.0..16.0)).collect::<Vec<f32>>();
+        let b = k.mul_vec(&to_f64(&x)).iter().map(|&b| b as f32).collect();
+        (key, k, x, b)
+    }
+
+    fn assert_solves(name: &str, solver: &dyn Solver<f32>, k: &FloatMatrix, x: &[f32], b: &[f32]) -> Convergence {
+        let tolerance = Tolerance { max_iterations: 500, ..Tolerance::default() };
+        let (solution, convergence) = solver.solve(k, b, &tolerance).unwrap();
+        assert!(convergence.converged, "{name}: {convergence:?}");
+        for (solution, x) in solution.iter().zip(x) {
+            assert!((solution - x).abs() < 1e-3, "{name}: {solution} instead of {x}");
+        }
+        convergence
+    }
+
+    #[test]
+    fn every_solver_converges_on_a_generated_key() {
+        for (n, seed) in [(8, 1), (33, 2)] {
+            let (_, k, x, b) = key_system(n, seed);
+            let solvers: [(&str, Box<dyn Solver<f32>>); 7] = [
+                ("jacobi", Box::new(Jacobi)),
+                ("gauss-seidel", Box::new(GaussSeidel)),
+                ("sor", Box::new(Sor { omega: 1.1 })),
+                ("ssor", Box::new(Ssor { omega: 1.0 })),
+                ("cg", Box::new(ConjugateGradient::default())),
+                ("gmres", Box::new(Gmres { restart: 10, preconditioner: None })),
+                ("bicgstab", Box::new(BiCgStab::default())),
+            ];
+            for (name, solver) in solvers {
+                assert_solves(&format!("{name} n={n}"), solver.as_ref(), &k, &x, &b);
+            }
+        }
+    }
+
+    #[test]
+    fn sor_stops_within_the_tolerance() {
+        let (_, k, _, b) = key_system(16, 4);
+        let b_norm = norm(&to_f64(&b));
+        let sor = Sor { omega: 1.1 };
+        let solve = |tolerance: Tolerance| Solver::<f32>::solve(&sor, &k, &b, &tolerance).unwrap().1;
+
+        // A looser tolerance stops earlier, as soon as the residual is within it.
+        let loose = solve(Tolerance { relative: 1e-2, max_iterations: 500, ..Tolerance::default() });
+        let tight = solve(Tolerance { relative: 1e-6, max_iterations: 500, ..Tolerance::default() });
+        assert!(loose.converged && tight.converged, "{loose:?} {tight:?}");
+        assert!(loose.residual <= 1e-2 * b_norm && tight.residual <= 1e-6 * b_norm);
+        assert!(loose.iterations < tight.iterations, "{loose:?} {tight:?}");
+
+        // The absolute tolerance is met by the starting `x = 0`.
+        let absolute = solve(Tolerance { absolute: b_norm, ..Tolerance::default() });
+        assert_eq!(absolute, Convergence { iterations: 0, residual: b_norm, converged: true });
+
+        // Out of iterations, the column is reported as not converged with its last residual.
+        let capped = solve(Tolerance { relative: 1e-6, max_iterations: 2, ..Tolerance::default() });
+        assert_eq!(capped.iterations, 2);
+        assert!(!capped.converged && capped.residual > 1e-6 * b_norm, "{capped:?}");
+    }
+
+    /// A Krylov solver with the given preconditioner.
+    type Preconditioned = fn(Box<dyn Preconditioner>) -> Box<dyn Solver<f32>>;
+
+    #[test]
+    fn every_preconditioner_converges_with_the_krylov_solvers() {
+        let (key, k, x, b) = key_system(24, 3);
+        let preconditioners = || -> [(&str, Box<dyn Preconditioner>); 4] {
+            [
+                ("jacobi", Box::new(JacobiPreconditioner::new(&k).unwrap())),
+                ("ilu0", Box::new(LuPreconditioner::ilu0(&k).unwrap())),
+                ("ssor", Box::new(LuPreconditioner::ssor(&k, 1.0).unwrap())),
+                ("exact", Box::new(LuPreconditioner::from_factors(&key.tril(), &key.triu()).unwrap())),
+            ]
+        };
+        let solvers: [(&str, Preconditioned); 3] = [
+            ("cg", |p| Box::new(ConjugateGradient { preconditioner: Some(p) })),
+            ("gmres", |p| Box::new(Gmres { restart: 10, preconditioner: Some(p) })),
+            ("bicgstab", |p| Box::new(BiCgStab { preconditioner: Some(p) })),
+        ];
+
+        for (solver_name, solver) in solvers {
+            for (name, preconditioner) in preconditioners() {
+                let exact = name == "exact";
+                let name = format!("{solver_name} {name}");
+                let convergence = assert_solves(&name, solver(preconditioner).as_ref(), &k, &x, &b);
+                if exact {
+                    // With the factors of the key, `A M^-1` is the identity.
+                    assert_eq!(convergence.iterations, 1, "{name}: {convergence:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blocked_lu_solve_matches_column_by_column() {
+        let mut rng = ChaCha20Rng::seed_from_u64(4);
+        let key = gen_key_with_rng(12, false, &mut rng).unwrap();
+        let (l, u) = (key.tril(), key.triu());
+
+        // Fewer columns than a block, a block and a bit, and several blocks and a bit.
+        for m in [1, TRSM_NC - 1, TRSM_NC, TRSM_NC + 1, 3 * TRSM_NC + 17] {
+            let b = FloatMatrix::random_with_rng(12, m, &0.0, &16.0, &mut rng);
+            let mut solved = b.clone();
+            Matrix::solve_system_lu_in_place(&l, &u, &mut solved).unwrap();
+
+            for j in 0..m {
+                let column = Matrix::solve_system_lu(&l, &u, (0..12).map(|i| b[i][j]).collect()).unwrap();
+                for (i, x) in column.iter().enumerate() {
+                    assert!((solved[i][j] - x).abs() <= 1e-5 * x.abs().max(1.0), "m={m} ({i}, {j})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dot_matches_the_naive_triple_loop() {
+        let mut rng = ChaCha20Rng::seed_from_u64(5);
+        // Small whole numbers, so that the sums are exact in any order.
+        let mut random = |n: usize, m: usize| {
+            Matrix::<f64>::from_vec(n, m, (0..n * m).map(|_| rng.gen_range(-8..8) as f64).collect()).unwrap()
+        };
+
+        // Around the tile sizes `DOT_MC`, `DOT_KC` and `DOT_NC`.
+        for (n, k, m) in [(63, 65, 129), (65, 129, 63), (129, 513, 65), (63, 63, 513), (1, 129, 1)] {
+            let (lhs, rhs) = (random(n, k), random(k, m));
+            let res = lhs.dot(&rhs).unwrap();
+            assert_eq!((res.n, res.m), (n, m));
+            for i in 0..n {
+                for j in 0..m {
+                    let expected = (0..k).map(|l| lhs[i][l] * rhs[l][j]).sum::<f64>();
+                    assert_eq!(res[i][j], expected, "{n}x{k} times {k}x{m} at ({i}, {j})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zp_arithmetic_wraps_around_the_modulus() {
+        let a = Z257::new(200);
+        let b = Z257::new(100);
+
+        assert_eq!(Z257::new(257 + 5), Z257::new(5));
+        assert_eq!((a + b).value(), 43);
+        assert_eq!((b - a).value(), 157);
+        assert_eq!((a - a).value(), 0);
+        assert_eq!((a * b).value(), 20000 % 257);
+        assert_eq!(Z257::new(3).pow(256), Z257::ONE);
+        assert_eq!([a, b, Z257::new(1)].into_iter().sum::<Z257>().value(), 44);
+    }
+
+    #[test]
+    fn zp_inverts_every_nonzero_element() {
+        assert_eq!(Z257::ZERO.inverse(), None);
+        for value in 1..257 {
+            let a = Z257::new(value);
+            let inverse = a.inverse().unwrap();
+            assert_eq!(a * inverse, Z257::ONE, "{value}");
+            assert_eq!(Z257::ONE / a, inverse);
+            assert_eq!(a.dpow(-1), inverse);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "division by zero")]
+    fn zp_division_by_zero_panics() {
+        let _ = Z257::ONE / Z257::ZERO;
+    }
+}
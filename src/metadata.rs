@@ -0,0 +1,241 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    container::{ContainerHeader, PayloadType},
+    encryption::{Key, Mode},
+    error::{Error, Result},
+    riff::{chunk_len, find_chunk, parse_chunks, skip, Chunk, CIPHER_CHUNKS, METADATA_CHUNK},
+    SolutionMethod,
+};
+
+/// ## Wav metadata
+/// The chunks of a `.wav` file other than `fmt ` and `data` (`LIST`, `cue `, ...), which are
+/// carried through encryption byte for byte. Those before the `data` chunk stay before it and
+/// those after it stay after it.
+///
+/// They are either copied into the encrypted file as they are, after the chunks of the cipher,
+/// or encrypted into a `cp1m` chunk before the `data` chunk, whose container also holds the
+/// length of the ones after it (`u64`, big-endian), and one after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WavMetadata {
+    /// The chunks before the `data` chunk.
+    pub chunks: Vec<Chunk>,
+    /// The number of bytes of the chunks after the `data` chunk.
+    pub trailing_len: u64,
+    /// Whether they are encrypted rather than copied.
+    pub encrypted: bool,
+}
+
+impl WavMetadata {
+    /// The chunks to write before the `data` chunk of the encrypted file.
+    pub fn leading_chunks(&self, key: &Key, mode: Mode) -> Result<Vec<Chunk>> {
+        if !self.encrypted {
+            return Ok(self.chunks.clone());
+        }
+        if self.chunks.is_empty() && self.trailing_len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut bytes = Vec::new();
+        for chunk in &self.chunks {
+            chunk.write_to(&mut bytes)?;
+        }
+        let data = seal(&bytes, self.trailing_len.to_be_bytes().to_vec(), key, mode)?;
+        Ok(vec![Chunk { id: METADATA_CHUNK, data }])
+    }
+
+    /// The number of bytes written after the `data` chunk (and the chunks of the cipher) of
+    /// the encrypted file.
+    pub fn stored_trailing_len(&self, key: &Key, mode: Mode) -> Result<u64> {
+        if !self.encrypted || self.trailing_len == 0 {
+            return Ok(self.trailing_len);
+        }
+
+        let header = ContainerHeader::new(PayloadType::AudioMetadata, Some(self.trailing_len), Vec::new(), key, mode)?;
+        let container_len = header
+            .container_len()
+            .ok_or_else(|| Error::UnsupportedFormat("the metadata is too long".to_string()))?;
+        Ok(chunk_len(container_len))
+    }
+
+    /// Copies (or encrypts) the chunks after the `data` chunk, which `reader` has to be at, to
+    /// the end of the encrypted file.
+    pub fn write_trailing<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        key: &Key,
+        mode: Mode,
+    ) -> Result<()> {
+        let mut bytes = Vec::new();
+        reader.take(self.trailing_len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != self.trailing_len {
+            return Err(Error::UnsupportedFormat("the wav file is truncated".to_string()));
+        }
+
+        if !self.encrypted {
+            writer.write_all(&bytes)?;
+        } else if !bytes.is_empty() {
+            let data = seal(&bytes, Vec::new(), key, mode)?;
+            Chunk { id: METADATA_CHUNK, data }.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    /// The metadata of the original file, from the chunks before the `data` chunk of the
+    /// encrypted file and the number of bytes after it (including the chunks of the cipher,
+    /// which `write_restored_trailing` is told about).
+    pub fn restore(chunks: Vec<Chunk>, trailing_len: u64, key: &Key, method: &SolutionMethod) -> Result<Self> {
+        let Some(stored) = chunks.iter().find(|chunk| chunk.id == METADATA_CHUNK) else {
+            return Ok(WavMetadata {
+                chunks: chunks.into_iter().filter(|chunk| !CIPHER_CHUNKS.contains(&chunk.id)).collect(),
+                trailing_len,
+                encrypted: false,
+            });
+        };
+
+        let (bytes, metadata) = open(&stored.data, key, method)?;
+        let trailing_len = <[u8; 8]>::try_from(metadata.as_slice())
+            .map(u64::from_be_bytes)
+            .map_err(|_| Error::MalformedCiphertext("invalid metadata chunk".to_string()))?;
+        Ok(WavMetadata { chunks: parse_chunks(&bytes)?, trailing_len, encrypted: true })
+    }
+
+    /// The number of bytes of the chunks after the `data` chunk of the decrypted file, given
+    /// the number of bytes of the chunks of the cipher after the `data` chunk of the encrypted
+    /// one.
+    pub fn restored_trailing_len(&self, cipher_len: u64) -> Result<u64> {
+        if self.encrypted {
+            return Ok(self.trailing_len);
+        }
+        self.trailing_len
+            .checked_sub(cipher_len)
+            .ok_or_else(|| Error::MalformedCiphertext("the wav file is truncated".to_string()))
+    }
+
+    /// Copies (or decrypts) the chunks after the `data` chunk of the original file to the end of
+    /// the decrypted file. `reader` has to be after the chunks of the cipher.
+    pub fn write_restored_trailing<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        cipher_len: u64,
+        key: &Key,
+        method: &SolutionMethod,
+    ) -> Result<()> {
+        let len = self.restored_trailing_len(cipher_len)?;
+        if !self.encrypted {
+            if io::copy(&mut reader.take(len), writer)? != len {
+                return Err(Error::MalformedCiphertext("the wav file is truncated".to_string()));
+            }
+            return Ok(());
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let size = find_chunk(reader, &METADATA_CHUNK)?
+            .ok_or_else(|| Error::MalformedCiphertext("missing cp1m chunk".to_string()))?;
+        let mut stored = Vec::new();
+        reader.take(size).read_to_end(&mut stored)?;
+        skip(reader, size & 1)?;
+        let (bytes, _) = open(&stored, key, method)?;
+        if bytes.len() as u64 != len {
+            return Err(Error::MalformedCiphertext("invalid metadata chunk".to_string()));
+        }
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+/// Encrypts the raw chunks into a container with the given payload metadata.
+fn seal(bytes: &[u8], metadata: Vec<u8>, key: &Key, mode: Mode) -> Result<Vec<u8>> {
+    let header = ContainerHeader::new(PayloadType::AudioMetadata, Some(bytes.len() as u64), metadata, key, mode)?;
+    let mut data = Vec::new();
+    header.seal(key, &mut &bytes[..], &mut data)?;
+    Ok(data)
+}
+
+/// Decrypts a container written by `seal` and returns the raw chunks and the payload metadata.
+fn open(data: &[u8], key: &Key, method: &SolutionMethod) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut reader = data;
+    let header = ContainerHeader::read_from(&mut reader)?;
+    header.check_payload(PayloadType::AudioMetadata)?;
+    let mut bytes = Vec::new();
+    // The metadata is not counted in the report of the samples.
+    header.open(key, method, &mut reader, &mut bytes)?;
+    Ok((bytes, header.metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{decrypt_audio_stream, encrypt_audio_stream, AudioOptions},
+        channels::ChannelSelection,
+        encryption::{gen_key, gen_modular_key, Precision},
+        riff::{write_wav_chunks, WavFormat, WAVE_FORMAT_PCM},
+    };
+
+    /// 16-bit stereo samples between odd-length `LIST` and `cue ` chunks and two chunks after
+    /// the `data` chunk.
+    fn wav() -> Vec<u8> {
+        let format = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 2,
+            sampling_rate: 8000,
+            bytes_per_second: 32000,
+            bytes_per_sample: 4,
+            bits_per_sample: 16,
+        };
+        let leading = [
+            Chunk { id: *b"LIST", data: b"INFOINAM\x05\0\0\0secret".to_vec() },
+            Chunk { id: *b"cue ", data: vec![1, 2, 3] },
+        ];
+        let trailing = [
+            Chunk { id: *b"id3 ", data: b"ID3 secret tag".to_vec() },
+            Chunk { id: *b"smpl", data: vec![9; 5] },
+        ];
+        let mut trailing_bytes = Vec::new();
+        for chunk in &trailing {
+            chunk.write_to(&mut trailing_bytes).unwrap();
+        }
+
+        let data_len = 4 * 1000;
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, &format.to_fmt(), &leading, data_len, trailing_bytes.len() as u64).unwrap();
+        wav.extend((0..data_len).map(|i| (i * 11 % 253) as u8));
+        wav.extend_from_slice(&trailing_bytes);
+        wav
+    }
+
+    #[test]
+    fn metadata_chunks_are_restored_byte_for_byte() {
+        let wav = wav();
+        let float = Key::Float(gen_key(8, false).unwrap());
+        let modular = Key::Modular(gen_modular_key(8).unwrap());
+        let single = Mode::Float(Precision::Single);
+        let layouts = [
+            (&float, single, AudioOptions::default()),
+            (&float, single, AudioOptions { ranges: vec!["0.01-0.02".parse().unwrap()], ..AudioOptions::default() }),
+            (&float, single, AudioOptions { channels: Some(ChannelSelection::Only(vec![2])), ..AudioOptions::default() }),
+            (&modular, Mode::Modular, AudioOptions { scramble: true, ..AudioOptions::default() }),
+        ];
+
+        for (key, mode, options) in layouts {
+            for encrypt_metadata in [false, true] {
+                let options = AudioOptions { encrypt_metadata, ..options.clone() };
+                let mut encrypted = Vec::new();
+                encrypt_audio_stream(&mut io::Cursor::new(&wav), &mut encrypted, key, mode, &options).unwrap();
+                // Copied chunks stay readable, encrypted ones do not.
+                let readable = |text: &[u8]| encrypted.windows(text.len()).any(|window| window == text);
+                assert_eq!(readable(b"INFOINAM"), !encrypt_metadata, "{options:?}");
+                assert_eq!(readable(b"ID3 secret tag"), !encrypt_metadata, "{options:?}");
+
+                let mut decrypted = Vec::new();
+                decrypt_audio_stream(&mut io::Cursor::new(&encrypted), &mut decrypted, key, &SolutionMethod::Direct).unwrap();
+                assert!(decrypted == wav, "{options:?}");
+            }
+        }
+    }
+}
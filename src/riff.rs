@@ -0,0 +1,419 @@
+use std::io::{self, Read, Write};
+
+use crate::error::{Error, Result};
+
+/// ## Wav format
+/// The contents of the `fmt ` chunk of a `.wav` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    /// `WAVE_FORMAT_PCM` or `WAVE_FORMAT_IEEE_FLOAT`.
+    pub audio_format: u16,
+    pub channel_count: u16,
+    pub sampling_rate: u32,
+    pub bytes_per_second: u32,
+    /// The number of bytes of one sample of every channel.
+    pub bytes_per_sample: u16,
+    pub bits_per_sample: u16,
+}
+
+impl WavFormat {
+    /// The contents of a plain (not extensible) `fmt ` chunk of the format.
+    pub fn to_fmt(&self) -> Vec<u8> {
+        [
+            self.audio_format.to_le_bytes().as_slice(),
+            &self.channel_count.to_le_bytes(),
+            &self.sampling_rate.to_le_bytes(),
+            &self.bytes_per_second.to_le_bytes(),
+            &self.bytes_per_sample.to_le_bytes(),
+            &self.bits_per_sample.to_le_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// ## Chunk
+/// A chunk of a `.wav` file other than `fmt ` and `data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// The number of bytes of the chunk in the file, including its header and padding.
+    pub fn len(&self) -> u64 {
+        chunk_len(self.data.len() as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Writes the chunk, including its header and padding.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> Result<()> {
+        write_chunk_header(writer, &self.id, self.data.len() as u64)?;
+        writer.write_all(&self.data)?;
+        if self.data.len() % 2 == 1 {
+            writer.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+/// The id of the chunk which holds the container header of a scrambled file, before the `data`
+/// chunk.
+pub const SCRAMBLED_HEADER_CHUNK: [u8; 4] = *b"cp1c";
+/// The id of the chunk which holds the end of the body of a scrambled file which does not fit
+/// into the samples, after the `data` chunk.
+pub const SCRAMBLED_TAIL_CHUNK: [u8; 4] = *b"cp1t";
+/// The id of the chunk which holds the encrypted segments, after the `data` chunk.
+pub const SEGMENTS_CHUNK: [u8; 4] = *b"cp1s";
+/// The id of the chunk which holds the encrypted channels, after the `data` chunk.
+pub const CHANNELS_CHUNK: [u8; 4] = *b"cp1l";
+/// The id of the chunks which hold the encrypted metadata chunks.
+pub const METADATA_CHUNK: [u8; 4] = *b"cp1m";
+/// The ids of all the chunks the cipher adds to `.wav` files.
+pub const CIPHER_CHUNKS: [[u8; 4]; 5] =
+    [SCRAMBLED_HEADER_CHUNK, SCRAMBLED_TAIL_CHUNK, SEGMENTS_CHUNK, CHANNELS_CHUNK, METADATA_CHUNK];
+
+/// The format of integer samples.
+pub const WAVE_FORMAT_PCM: u16 = 1;
+/// The format of floating point samples.
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// The format whose actual format is in the extension of the `fmt ` chunk.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// The length of the extension of `WAVE_FORMAT_EXTENSIBLE` up to and including the first two
+/// bytes of the sub-format GUID, which are the actual format.
+const EXTENSIBLE_LEN: usize = 2 + 2 + 4 + 2;
+
+/// The length of a plain `fmt ` chunk, without an extension.
+const FMT_LEN: usize = 16;
+/// The longest `fmt ` chunk we read, real ones are at most 40 bytes.
+const MAX_FMT_LEN: u32 = 1024;
+
+/// ## Read wav header
+/// Reads the chunks of a `.wav` file up to the start of the samples and returns the format and
+/// the length of the `data` chunk in bytes. The samples can then be read from `reader` (which
+/// may well be longer than the `data` chunk).
+/// ### Limiatations:
+/// - chunks other than `fmt ` and `data` are skipped.
+/// - the format of `WAVE_FORMAT_EXTENSIBLE` files is replaced by their sub-format (the
+///   `fmt ` chunk itself is kept by `read_wav_chunks`).
+pub fn read_wav_header<R: Read + ?Sized>(reader: &mut R) -> Result<(WavFormat, u64)> {
+    let header = read_wav_chunks(reader)?;
+    Ok((header.format, header.data_len))
+}
+
+/// ## Wav header
+/// Everything in a `.wav` file but the samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WavHeader {
+    pub format: WavFormat,
+    /// The contents of the `fmt ` chunk as they are, including any extension.
+    pub fmt: Vec<u8>,
+    /// The other chunks before the `data` chunk.
+    pub chunks: Vec<Chunk>,
+    /// The length of the `data` chunk.
+    pub data_len: u64,
+    /// The number of bytes of the chunks after the `data` chunk (and its padding), according to
+    /// the length of the RIFF chunk.
+    pub trailing_len: u64,
+}
+
+/// Same as `read_wav_header`, but also returns the other chunks before the `data` chunk and the
+/// length of the ones after it.
+pub fn read_wav_chunks<R: Read + ?Sized>(reader: &mut R) -> Result<WavHeader> {
+    let mut riff = [0; 12];
+    reader.read_exact(&mut riff).map_err(not_a_wav_file)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err(Error::UnsupportedFormat("not a wav file".to_string()));
+    }
+    let riff_end = 8 + u32::from_le_bytes(riff[4..8].try_into().unwrap()) as u64;
+
+    let mut fmt = None;
+    let mut chunks = Vec::new();
+    let mut position = riff.len() as u64;
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk).map_err(not_a_wav_file)?;
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        position += chunk_len(size as u64);
+
+        match &chunk[..4] {
+            b"fmt " => {
+                if size > MAX_FMT_LEN {
+                    return Err(Error::UnsupportedFormat("the fmt chunk is too long".to_string()));
+                }
+                let mut contents = vec![0; padded(size) as usize];
+                reader.read_exact(&mut contents).map_err(not_a_wav_file)?;
+                contents.truncate(size as usize);
+                parse_fmt(&contents)?;
+                fmt = Some(contents);
+            }
+            b"data" => {
+                let fmt = fmt.ok_or_else(|| {
+                    Error::UnsupportedFormat("the data chunk comes before the fmt chunk".to_string())
+                })?;
+                let format = parse_fmt(&fmt)?;
+                // Streamed files often do not know the length of the RIFF chunk, in which case
+                // whatever follows the samples is ignored.
+                let trailing_len = match riff_end {
+                    end if end == u32::MAX as u64 + 8 => 0,
+                    end => end.saturating_sub(position),
+                };
+                return Ok(WavHeader { format, fmt, chunks, data_len: size as u64, trailing_len });
+            }
+            _ => {
+                let mut data = Vec::new();
+                reader.take(padded(size)).read_to_end(&mut data)?;
+                if data.len() as u64 != padded(size) {
+                    return Err(Error::UnsupportedFormat("the wav file is truncated".to_string()));
+                }
+                data.truncate(size as usize);
+                chunks.push(Chunk { id: chunk[..4].try_into().unwrap(), data });
+            }
+        }
+    }
+}
+
+/// Parses the contents of a `fmt ` chunk. The format of `WAVE_FORMAT_EXTENSIBLE` files is
+/// replaced by their sub-format.
+pub fn parse_fmt(fmt: &[u8]) -> Result<WavFormat> {
+    if fmt.len() < FMT_LEN {
+        return Err(Error::UnsupportedFormat("the fmt chunk is too short".to_string()));
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(fmt[i..i + 4].try_into().unwrap());
+    let mut audio_format = u16_at(0);
+    if audio_format == WAVE_FORMAT_EXTENSIBLE && fmt.len() >= FMT_LEN + EXTENSIBLE_LEN {
+        audio_format = u16_at(FMT_LEN + EXTENSIBLE_LEN - 2);
+    }
+    Ok(WavFormat {
+        audio_format,
+        channel_count: u16_at(2),
+        sampling_rate: u32_at(4),
+        bytes_per_second: u32_at(8),
+        bytes_per_sample: u16_at(12),
+        bits_per_sample: u16_at(14),
+    })
+}
+
+/// Writes the header of a `.wav` file with `data_len` bytes of samples. The samples (followed by
+/// a zero byte if `data_len` is odd) have to be written to `writer` afterwards.
+pub fn write_wav_header<W: Write + ?Sized>(writer: &mut W, format: &WavFormat, data_len: u64) -> Result<()> {
+    write_wav_chunks(writer, &format.to_fmt(), &[], data_len, 0)
+}
+
+/// Same as `write_wav_header`, but writes the given contents of the `fmt ` chunk as they are and
+/// the given chunks before the `data` chunk, which is followed by `trailing_len` bytes of other
+/// chunks (including their headers and padding).
+pub fn write_wav_chunks<W: Write + ?Sized>(
+    writer: &mut W,
+    fmt: &[u8],
+    chunks: &[Chunk],
+    data_len: u64,
+    trailing_len: u64,
+) -> Result<()> {
+    let fmt_chunk = Chunk { id: *b"fmt ", data: fmt.to_vec() };
+    let chunks_len = chunks.iter().map(Chunk::len).sum::<u64>();
+    // "WAVE" + the `fmt ` chunk + the other chunks + the `data` chunk.
+    let riff_len = u32::try_from(4 + fmt_chunk.len() + chunks_len + chunk_len(data_len) + trailing_len)
+        .map_err(|_| Error::UnsupportedFormat(format!("{data_len} bytes are too much for a wav file")))?;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_len.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    fmt_chunk.write_to(writer)?;
+    for chunk in chunks {
+        chunk.write_to(writer)?;
+    }
+    write_chunk_header(writer, b"data", data_len)?;
+
+    Ok(())
+}
+
+/// Writes the header of a chunk of `len` bytes. The contents (followed by a zero byte if `len` is
+/// odd) have to be written to `writer` afterwards.
+pub fn write_chunk_header<W: Write + ?Sized>(writer: &mut W, id: &[u8; 4], len: u64) -> Result<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| Error::UnsupportedFormat(format!("{len} bytes are too much for a wav chunk")))?;
+    writer.write_all(id)?;
+    writer.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+/// Parses the chunks of `bytes`, the inverse of writing them one after the other.
+pub fn parse_chunks(mut bytes: &[u8]) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    while !bytes.is_empty() {
+        let truncated = || Error::MalformedCiphertext("the chunks are truncated".to_string());
+        let header = bytes.get(..8).ok_or_else(truncated)?;
+        let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let data = bytes.get(8..8 + size).ok_or_else(truncated)?;
+        chunks.push(Chunk { id: header[..4].try_into().unwrap(), data: data.to_vec() });
+        bytes = bytes.get(chunk_len(size as u64) as usize..).unwrap_or_default();
+    }
+    Ok(chunks)
+}
+
+/// Skips chunks until the one with the given id and returns its length, which can then be read
+/// from `reader`. Returns `None` if the file ends first.
+pub fn find_chunk<R: Read + ?Sized>(reader: &mut R, id: &[u8; 4]) -> Result<Option<u64>> {
+    Ok(find_any_chunk(reader, &[*id])?.map(|(_, len)| len))
+}
+
+/// Same as `find_chunk`, but stops at the first chunk with any of the given ids and returns its
+/// id too.
+pub fn find_any_chunk<R: Read + ?Sized>(reader: &mut R, ids: &[[u8; 4]]) -> Result<Option<([u8; 4], u64)>> {
+    loop {
+        let mut chunk = [0; 8];
+        match reader.read_exact(&mut chunk) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let id: [u8; 4] = chunk[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        if ids.contains(&id) {
+            return Ok(Some((id, size as u64)));
+        }
+        skip(reader, padded(size))?;
+    }
+}
+
+/// The length of a chunk with `len` bytes of contents, including its header and padding.
+pub fn chunk_len(len: u64) -> u64 {
+    8 + padded_len(len)
+}
+
+/// Chunks are padded to an even length.
+pub fn padded_len(len: u64) -> u64 {
+    len + (len & 1)
+}
+
+fn padded(size: u32) -> u64 {
+    padded_len(size as u64)
+}
+
+/// Skips `len` bytes of `reader`.
+pub fn skip<R: Read + ?Sized>(reader: &mut R, len: u64) -> Result<()> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(Error::UnsupportedFormat("the wav file is truncated".to_string()));
+    }
+    Ok(())
+}
+
+/// A wav file which ends before its samples start is not one.
+fn not_a_wav_file(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::UnsupportedFormat("not a wav file".to_string()),
+        _ => Error::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(audio_format: u16, bits_per_sample: u16) -> WavFormat {
+        let bytes_per_sample = 2 * bits_per_sample / 8;
+        WavFormat {
+            audio_format,
+            channel_count: 2,
+            sampling_rate: 8000,
+            bytes_per_second: 8000 * bytes_per_sample as u32,
+            bytes_per_sample,
+            bits_per_sample,
+        }
+    }
+
+    /// The `fmt ` chunk of a `WAVE_FORMAT_EXTENSIBLE` file with the given sub-format.
+    fn extensible_fmt(sub_format: u16, bits_per_sample: u16) -> Vec<u8> {
+        let mut fmt = format(WAVE_FORMAT_EXTENSIBLE, bits_per_sample).to_fmt();
+        fmt.extend_from_slice(&22u16.to_le_bytes());
+        fmt.extend_from_slice(&bits_per_sample.to_le_bytes());
+        // front left and right
+        fmt.extend_from_slice(&3u32.to_le_bytes());
+        fmt.extend_from_slice(&sub_format.to_le_bytes());
+        fmt.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71]);
+        fmt
+    }
+
+    /// A `.wav` file with the given `fmt ` chunk, a `LIST` chunk before the odd number of bytes
+    /// of samples and a `cue ` chunk after them.
+    fn wav(fmt: &[u8]) -> Vec<u8> {
+        let list = Chunk { id: *b"LIST", data: b"INFOtest".to_vec() };
+        let cue = Chunk { id: *b"cue ", data: vec![7; 5] };
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, fmt, &[list], 5, cue.len()).unwrap();
+        wav.extend_from_slice(&[1, 2, 3, 4, 5, 0]);
+        cue.write_to(&mut wav).unwrap();
+        wav
+    }
+
+    #[test]
+    fn wav_chunks_roundtrip() {
+        let formats = [
+            format(WAVE_FORMAT_PCM, 8),
+            format(WAVE_FORMAT_PCM, 16),
+            format(WAVE_FORMAT_PCM, 24),
+            format(WAVE_FORMAT_PCM, 32),
+            format(WAVE_FORMAT_IEEE_FLOAT, 32),
+        ];
+        for format in formats {
+            let wav = wav(&format.to_fmt());
+            let mut reader = &wav[..];
+            let header = read_wav_chunks(&mut reader).unwrap();
+            assert_eq!(header.format, format);
+            assert_eq!(header.fmt, format.to_fmt());
+            assert_eq!(header.chunks, vec![Chunk { id: *b"LIST", data: b"INFOtest".to_vec() }]);
+            assert_eq!(header.data_len, 5);
+            assert_eq!(header.trailing_len, 8 + 6);
+            assert_eq!(&reader[..5], &[1, 2, 3, 4, 5]);
+            assert_eq!(wav.len() as u64, 8 + u32::from_le_bytes(wav[4..8].try_into().unwrap()) as u64);
+        }
+    }
+
+    #[test]
+    fn extended_fmt_chunks_are_kept() {
+        // cbSize = 0, cbSize = 2 with two more bytes, and the extensible format.
+        let mut empty = format(WAVE_FORMAT_PCM, 16).to_fmt();
+        empty.extend_from_slice(&0u16.to_le_bytes());
+        let mut short = format(WAVE_FORMAT_PCM, 16).to_fmt();
+        short.extend_from_slice(&2u16.to_le_bytes());
+        short.extend_from_slice(&[0xAB, 0xCD]);
+        let cases = [
+            (empty, WAVE_FORMAT_PCM, 16),
+            (short, WAVE_FORMAT_PCM, 16),
+            (extensible_fmt(WAVE_FORMAT_PCM, 24), WAVE_FORMAT_PCM, 24),
+            (extensible_fmt(WAVE_FORMAT_IEEE_FLOAT, 32), WAVE_FORMAT_IEEE_FLOAT, 32),
+        ];
+        for (fmt, audio_format, bits_per_sample) in cases {
+            let wav = wav(&fmt);
+            let header = read_wav_chunks(&mut &wav[..]).unwrap();
+            assert_eq!(header.fmt, fmt);
+            assert_eq!(header.format.audio_format, audio_format);
+            assert_eq!(header.format.bits_per_sample, bits_per_sample);
+
+            let mut rewritten = Vec::new();
+            write_wav_chunks(&mut rewritten, &header.fmt, &header.chunks, header.data_len, header.trailing_len).unwrap();
+            assert_eq!(rewritten, wav[..rewritten.len()]);
+        }
+    }
+
+    #[test]
+    fn cipher_chunks_are_distinct() {
+        for (i, id) in CIPHER_CHUNKS.iter().enumerate() {
+            assert!(!CIPHER_CHUNKS[i + 1..].contains(id), "{}", String::from_utf8_lossy(id));
+        }
+    }
+
+    #[test]
+    fn broken_fmt_chunks_are_rejected() {
+        let short = wav(&format(WAVE_FORMAT_PCM, 16).to_fmt()[..14]);
+        assert!(matches!(read_wav_chunks(&mut &short[..]), Err(Error::UnsupportedFormat(_))));
+        let long = wav(&vec![0; MAX_FMT_LEN as usize + 2]);
+        assert!(matches!(read_wav_chunks(&mut &long[..]), Err(Error::UnsupportedFormat(_))));
+    }
+}
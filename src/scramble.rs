@@ -0,0 +1,268 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    audio::{header_from_metadata, header_to_metadata, sample_width, SwapBytes},
+    container::{ContainerHeader, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::{Error, Result},
+    metadata::WavMetadata,
+    riff::{
+        chunk_len, find_chunk, parse_fmt, skip, write_wav_chunks, Chunk, WavFormat,
+        SCRAMBLED_HEADER_CHUNK, SCRAMBLED_TAIL_CHUNK, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM,
+    },
+    stream::encrypt_stream,
+    SolutionMethod,
+};
+
+/// The `fmt ` chunk of the scrambled file: the original one, except that float samples are
+/// stored as integers, since not every bit pattern is a float a player can handle.
+fn scrambled_fmt(fmt: &[u8], format: &WavFormat) -> Vec<u8> {
+    match format.audio_format {
+        WAVE_FORMAT_IEEE_FLOAT => WavFormat { audio_format: WAVE_FORMAT_PCM, ..*format }.to_fmt(),
+        _ => fmt.to_vec(),
+    }
+}
+
+/// ### Encryption:
+/// 1. encrypt the samples (as big-endian bytes) with the key matrix over `Z_257`, which gives
+///    a byte for every byte, except for the padding of the last column
+/// 2. store the header of the container in a `cp1c` chunk before the `data` chunk
+/// 3. store the encrypted bytes as the samples, so the file is just as long and plays as noise
+/// 4. store the rest of the body (the encrypted padding) in a `cp1t` chunk after the `data`
+///    chunk, followed by the metadata chunks after the `data` chunk of the original file
+///
+/// `reader` has to be at the start of the `data_len` bytes of samples.
+pub fn encrypt_scrambled_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    fmt: &[u8],
+    data_len: u64,
+    metadata: &WavMetadata,
+    key: &Key,
+    mode: Mode,
+) -> Result<()> {
+    if mode != Mode::Modular {
+        return Err(Error::InvalidArgument(
+            "scrambled wav files can only be encrypted in the modular mode".to_string(),
+        ));
+    }
+    let format = parse_fmt(fmt)?;
+    let width = sample_width(&format)?;
+
+    let header = ContainerHeader::new(PayloadType::Audio, Some(data_len), header_to_metadata(fmt), key, mode)?;
+    header.check_key(key)?;
+    let container_len = header
+        .container_len()
+        .ok_or_else(|| Error::UnsupportedFormat("the wav file is too long".to_string()))?;
+    let tail_len = container_len - header.header_len() as u64 - data_len;
+
+    // 2. store the header of the container
+    let mut header_chunk = Chunk { id: SCRAMBLED_HEADER_CHUNK, data: Vec::new() };
+    header.write_to(&mut header_chunk.data)?;
+    let mut chunks = vec![header_chunk];
+    chunks.extend(metadata.leading_chunks(key, mode)?);
+    let trailing_len = chunk_len(tail_len) + metadata.stored_trailing_len(key, mode)?;
+    write_wav_chunks(writer, &scrambled_fmt(fmt, &format), &chunks, data_len, trailing_len)?;
+
+    // 1. encrypt the samples, 3. store them and 4. store the rest of the body
+    let mut samples = SwapBytes::new(reader.take(data_len), width);
+    let mut body = SplitWriter { inner: &mut *writer, left: data_len, tail: Vec::new() };
+    let read = encrypt_stream(&mut samples, &mut body, key, mode)?;
+    if read != data_len {
+        return Err(Error::UnsupportedFormat("the wav file is truncated".to_string()));
+    }
+    let tail = body.tail;
+    if data_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    Chunk { id: SCRAMBLED_TAIL_CHUNK, data: tail }.write_to(writer)?;
+    skip(reader, data_len & 1)?;
+    metadata.write_trailing(reader, writer, key, mode)?;
+
+    Ok(())
+}
+
+/// ### Decryption:
+/// 1. parse the container header from the `cp1c` chunk
+/// 2. check the key against it and decrypt the samples followed by the rest of the body from
+///    the `cp1t` chunk with the key matrix
+/// 3. store the decrypted samples into a decrypted audio file with the original format, along
+///    with the metadata chunks
+///
+/// `reader` has to be at the start of the `data_len` bytes of samples.
+pub fn decrypt_scrambled_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    header_chunk: &[u8],
+    data_len: u64,
+    metadata: &WavMetadata,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    // 1. parse the container header
+    let header = ContainerHeader::read_from(&mut &header_chunk[..])?;
+    header.check_payload(PayloadType::Audio)?;
+    let (format, fmt, rest) = header_from_metadata(&header.metadata)?;
+    let width = sample_width(&format)?;
+    if !rest.is_empty() || header.length != Some(data_len) || !data_len.is_multiple_of(width as u64) {
+        return Err(Error::MalformedCiphertext("invalid length header".to_string()));
+    }
+    let tail_len = header
+        .container_len()
+        .and_then(|len| len.checked_sub(header.header_len() as u64 + data_len))
+        .ok_or_else(|| Error::MalformedCiphertext("invalid length header".to_string()))?;
+    header.check_decryptable(key, method)?;
+
+    // 2. decrypt the samples and the rest of the body, 3. store them
+    let trailing_len = metadata.restored_trailing_len(chunk_len(tail_len))?;
+    write_wav_chunks(writer, fmt, &metadata.chunks, data_len, trailing_len)?;
+    let mut body = ScrambledBody { inner: reader, left: data_len, padded: data_len % 2 == 1, tail: None };
+    let mut out = SwapBytes::new(&mut *writer, width);
+    let report = header.open(key, method, &mut body, &mut out)?;
+    out.finish()?;
+    body.finish()?;
+    if data_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    metadata.write_restored_trailing(reader, writer, chunk_len(tail_len), key, method)?;
+
+    Ok(report)
+}
+
+/// Writes the first `left` bytes to `inner` and keeps the rest.
+struct SplitWriter<W> {
+    inner: W,
+    left: u64,
+    tail: Vec<u8>,
+}
+
+impl<W: Write> Write for SplitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let head = buf.len().min(self.left as usize);
+        self.inner.write_all(&buf[..head])?;
+        self.left -= head as u64;
+        self.tail.extend_from_slice(&buf[head..]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the `left` bytes of samples, followed by the contents of the `cp1t` chunk.
+struct ScrambledBody<'a, R: ?Sized> {
+    inner: &'a mut R,
+    left: u64,
+    /// Whether the samples are followed by a padding byte.
+    padded: bool,
+    tail: Option<io::Cursor<Vec<u8>>>,
+}
+
+impl<R: Read + ?Sized> Read for ScrambledBody<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left > 0 {
+            let len = buf.len().min(self.left as usize);
+            let read = self.inner.read(&mut buf[..len])?;
+            if read == 0 {
+                // The samples are truncated, so is the body.
+                self.left = 0;
+                self.tail = Some(io::Cursor::new(Vec::new()));
+                return Ok(0);
+            }
+            self.left -= read as u64;
+            return Ok(read);
+        }
+
+        if self.tail.is_none() {
+            self.tail = Some(io::Cursor::new(self.read_tail().map_err(io::Error::other)?));
+        }
+        self.tail.as_mut().unwrap().read(buf)
+    }
+}
+
+impl<R: Read + ?Sized> ScrambledBody<'_, R> {
+    /// Reads the `cp1t` chunk, if the body did not need it, so that `inner` is right after it.
+    fn finish(mut self) -> Result<()> {
+        if self.tail.is_none() {
+            skip(&mut *self.inner, self.left)?;
+            self.read_tail()?;
+        }
+        Ok(())
+    }
+
+    /// Skips the padding of the `data` chunk and reads the `cp1t` chunk.
+    fn read_tail(&mut self) -> Result<Vec<u8>> {
+        if self.padded {
+            skip(&mut *self.inner, 1)?;
+        }
+        let len = find_chunk(&mut *self.inner, &SCRAMBLED_TAIL_CHUNK)?
+            .ok_or_else(|| Error::MalformedCiphertext("missing cp1t chunk".to_string()))?;
+        let mut tail = Vec::new();
+        (&mut *self.inner).take(len).read_to_end(&mut tail)?;
+        skip(&mut *self.inner, len & 1)?;
+        Ok(tail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{decrypt_audio_stream, encrypt_audio_stream, AudioOptions},
+        encryption::gen_modular_key,
+        riff::read_wav_chunks,
+    };
+
+    #[test]
+    fn scrambled_samples_are_as_long_as_the_original() {
+        // 8-bit mono with an odd number of samples and 32-bit float stereo
+        let formats = [
+            WavFormat {
+                audio_format: WAVE_FORMAT_PCM,
+                channel_count: 1,
+                sampling_rate: 8000,
+                bytes_per_second: 8000,
+                bytes_per_sample: 1,
+                bits_per_sample: 8,
+            },
+            WavFormat {
+                audio_format: WAVE_FORMAT_IEEE_FLOAT,
+                channel_count: 2,
+                sampling_rate: 8000,
+                bytes_per_second: 64000,
+                bytes_per_sample: 8,
+                bits_per_sample: 32,
+            },
+        ];
+        let key = Key::Modular(gen_modular_key(8).unwrap());
+        let options = AudioOptions { scramble: true, ..AudioOptions::default() };
+
+        for format in formats {
+            let data_len = 1001 * format.bytes_per_sample as u64;
+            let mut wav = Vec::new();
+            write_wav_chunks(&mut wav, &format.to_fmt(), &[], data_len, 0).unwrap();
+            let samples = (0..data_len).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+            wav.extend_from_slice(&samples);
+            if data_len % 2 == 1 {
+                wav.push(0);
+            }
+
+            let mut scrambled = Vec::new();
+            encrypt_audio_stream(&mut io::Cursor::new(&wav), &mut scrambled, &key, Mode::Modular, &options).unwrap();
+            let header = read_wav_chunks(&mut scrambled.as_slice()).unwrap();
+            assert_eq!(header.data_len, data_len);
+            assert_eq!(header.format.audio_format, WAVE_FORMAT_PCM);
+            assert_eq!(header.format.bits_per_sample, format.bits_per_sample);
+            // Only the encrypted padding of the last column is left for the `cp1t` chunk.
+            assert!(header.trailing_len <= chunk_len(key.n() as u64 - 1));
+
+            let position = scrambled.len() - header.trailing_len as usize - (data_len + data_len % 2) as usize;
+            assert!(scrambled[position..position + samples.len()] != samples[..]);
+
+            let mut decrypted = Vec::new();
+            decrypt_audio_stream(&mut io::Cursor::new(&scrambled), &mut decrypted, &key, &SolutionMethod::Direct).unwrap();
+            assert_eq!(decrypted, wav);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+};
+
+use crate::{
+    audio::{copy_exact, frame_len, header_from_metadata, header_to_metadata, sample_width, silence, SideChunk, SwapBytes},
+    container::{ContainerHeader, PayloadType},
+    encryption::{Key, Mode, SolveReport},
+    error::{Error, Result},
+    metadata::WavMetadata,
+    riff::{chunk_len, parse_fmt, skip, write_chunk_header, write_wav_chunks, SEGMENTS_CHUNK},
+    stream::Sections,
+    SolutionMethod,
+};
+
+/// ## Time range
+/// A part of a recording, `<start>-<end>`, both given as `[[hh:]mm:]ss[.fff]`
+/// (e.g. `00:01.5-00:03.0`). The times are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |t: f64| format!("{:02}:{:06.3}", (t / 60.0) as u64, t % 60.0);
+        write!(f, "{}-{}", time(self.start), time(self.end))
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split_once('-')
+            .and_then(|(start, end)| Some(TimeRange { start: parse_time(start)?, end: parse_time(end)? }))
+            .filter(|range| range.start < range.end)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "invalid range `{s}` (expected `<start>-<end>` like `00:01.5-00:03.0`)"
+                ))
+            })
+    }
+}
+
+/// Parses `[[hh:]mm:]ss[.fff]` into seconds.
+fn parse_time(s: &str) -> Option<f64> {
+    let mut parts = s.trim().rsplit(':');
+    let seconds = parts.next()?.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0)?;
+    let mut time = seconds;
+    for scale in [60.0, 3600.0] {
+        if let Some(part) = parts.next() {
+            time += part.parse::<u64>().ok()? as f64 * scale;
+        }
+    }
+    parts.next().is_none().then_some(time)
+}
+
+/// ## Segment
+/// A run of frames (one sample of every channel) which is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: u64,
+    pub frames: u64,
+}
+
+/// The segments the time ranges cover in a recording of `frames` frames, sorted and with the
+/// overlapping ones merged.
+pub fn segments_from_ranges(ranges: &[TimeRange], sampling_rate: u32, frames: u64) -> Result<Vec<Segment>> {
+    let mut bounds = ranges
+        .iter()
+        .map(|range| {
+            let frame = |t: f64| ((t * sampling_rate as f64).round() as u64).min(frames);
+            let (start, end) = (frame(range.start), frame(range.end));
+            if start >= end {
+                return Err(Error::InvalidArgument(format!(
+                    "the range `{range}` is not inside the recording, which is {:.3} s long",
+                    frames as f64 / sampling_rate.max(1) as f64
+                )));
+            }
+            Ok((start, end))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    bounds.sort();
+
+    let mut segments: Vec<Segment> = Vec::with_capacity(bounds.len());
+    for (start, end) in bounds {
+        match segments.last_mut() {
+            Some(last) if start <= last.start + last.frames => {
+                last.frames = last.frames.max(end - last.start);
+            }
+            _ => segments.push(Segment { start, frames: end - start }),
+        }
+    }
+
+    Ok(segments)
+}
+
+/// The segment map: the first frame and the number of frames of every segment (`u64`s,
+/// big-endian).
+pub fn segments_to_bytes(segments: &[Segment]) -> Vec<u8> {
+    segments
+        .iter()
+        .flat_map(|segment| [segment.start.to_be_bytes(), segment.frames.to_be_bytes()])
+        .flatten()
+        .collect()
+}
+
+/// The inverse of `segments_to_bytes`. The segments have to be sorted and must not overlap.
+pub fn segments_from_bytes(bytes: &[u8]) -> Result<Vec<Segment>> {
+    let invalid = || Error::MalformedCiphertext("invalid segment map".to_string());
+    if !bytes.len().is_multiple_of(16) {
+        return Err(invalid());
+    }
+
+    let u64_at = |pair: &[u8], i: usize| u64::from_be_bytes(pair[i..i + 8].try_into().unwrap());
+    let segments = bytes
+        .chunks_exact(16)
+        .map(|pair| Segment { start: u64_at(pair, 0), frames: u64_at(pair, 8) })
+        .collect::<Vec<_>>();
+    let mut end = 0;
+    for segment in &segments {
+        if segment.start < end || segment.frames == 0 {
+            return Err(invalid());
+        }
+        end = segment.start.checked_add(segment.frames).ok_or_else(invalid)?;
+    }
+
+    Ok(segments)
+}
+
+/// ### Encryption:
+/// 1. turn the time ranges into segments of frames
+/// 2. copy the samples, replacing the ones of the segments with silence, so the rest of the
+///    recording stays playable
+/// 3. encrypt the samples of the segments (as big-endian bytes) with the key matrix in the given
+///    mode into a container, which also holds the format and the segment map
+/// 4. store the container in a `cp1s` chunk after the `data` chunk, followed by the metadata
+///    chunks after the `data` chunk of the original file
+///
+/// `reader` has to be at the start of the `data_len` bytes of samples. If it can seek, the
+/// samples of the segments are read again for step 3, otherwise they are kept in memory until
+/// they are encrypted.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_segments_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    fmt: &[u8],
+    data_len: u64,
+    metadata: &WavMetadata,
+    ranges: &[TimeRange],
+    key: &Key,
+    mode: Mode,
+) -> Result<()> {
+    // 1. turn the time ranges into segments of frames
+    let format = parse_fmt(fmt)?;
+    let width = sample_width(&format)?;
+    let frame_len = frame_len(&format, width, data_len)?;
+    let segments = segments_from_ranges(ranges, format.sampling_rate, data_len / frame_len)?;
+    let segments_len = segments.iter().map(|segment| segment.frames * frame_len).sum();
+
+    let mut payload_metadata = header_to_metadata(fmt);
+    payload_metadata.extend_from_slice(&segments_to_bytes(&segments));
+    let header =
+        ContainerHeader::new(PayloadType::AudioSegments, Some(segments_len), payload_metadata, key, mode)?;
+    header.check_key(key)?;
+    let container_len = header
+        .container_len()
+        .ok_or_else(|| Error::UnsupportedFormat("the segments are too long".to_string()))?;
+    write_wav_chunks(
+        writer,
+        fmt,
+        &metadata.leading_chunks(key, mode)?,
+        data_len,
+        chunk_len(container_len) + metadata.stored_trailing_len(key, mode)?,
+    )?;
+
+    // 2. copy the samples, replacing the ones of the segments with silence
+    let data_start = reader.stream_position().ok();
+    let silence = silence(&format, width);
+    let mut samples = Vec::new();
+    let mut position = 0;
+    for segment in &segments {
+        let start = segment.start * frame_len;
+        let len = segment.frames * frame_len;
+        copy_exact(reader, writer, start - position)?;
+        match data_start {
+            Some(_) => copy_exact(reader, &mut io::sink(), len)?,
+            None => copy_exact(reader, &mut samples, len)?,
+        }
+        io::copy(&mut io::repeat(silence).take(len), writer)?;
+        position = start + len;
+    }
+    copy_exact(reader, writer, data_len - position)?;
+    if data_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    // 3. encrypt the samples of the segments and 4. store them after the `data` chunk
+    write_chunk_header(writer, &SEGMENTS_CHUNK, container_len)?;
+    match data_start {
+        Some(data_start) => {
+            let sections = Sections::new(&mut *reader)?;
+            let mut selected = segments.iter().map(|segment| {
+                sections.section(data_start + segment.start * frame_len, segment.frames * frame_len)
+            });
+            let mut samples = ChainAll { current: selected.next(), rest: selected };
+            header.seal(key, &mut SwapBytes::new(&mut samples, width), writer)?;
+            sections.into_inner().seek(SeekFrom::Start(data_start + data_len))?;
+        }
+        None => header.seal(key, &mut SwapBytes::new(samples.as_slice(), width), writer)?,
+    }
+    if container_len % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    skip(reader, data_len & 1)?;
+    metadata.write_trailing(reader, writer, key, mode)?;
+
+    Ok(())
+}
+
+/// ### Decryption:
+/// 1. find the `cp1s` chunk after the `data` chunk (see `SideChunk`)
+/// 2. check the key against the container and decrypt the samples of the segments with the key
+///    matrix
+/// 3. put the samples back in place of the silence
+/// 4. store the samples into a decrypted audio file with the original format, along with the
+///    metadata chunks
+///
+/// `reader` has to be right after the header of the container.
+pub(crate) fn decrypt_segments_stream<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    side: &SideChunk,
+    metadata: &WavMetadata,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    // 1. find the `cp1s` chunk after the `data` chunk
+    let header = &side.header;
+    header.check_payload(PayloadType::AudioSegments)?;
+    let (format, fmt, map) = header_from_metadata(&header.metadata)?;
+    let segments = segments_from_bytes(map)?;
+
+    let width = sample_width(&format)?;
+    let frame_len = (width * format.channel_count as usize) as u64;
+    let fits = segments.last().is_none_or(|last| {
+        (last.start + last.frames).checked_mul(frame_len).is_some_and(|end| end <= side.data_len)
+    });
+    let segments_len: u64 = segments.iter().map(|segment| segment.frames * frame_len).sum();
+    if !fits || header.length != Some(segments_len) {
+        return Err(Error::MalformedCiphertext("invalid segment map".to_string()));
+    }
+    header.check_decryptable(key, method)?;
+
+    // 2. decrypt the samples of the segments and 3. put them back in place of the silence,
+    //    4. store the samples
+    side.restore(reader, writer, fmt, metadata, key, method, |samples, body, writer| {
+        let mut out = Splicer::new(samples, writer, &segments, frame_len);
+        let report = header.open(key, method, body, &mut SwapBytes::new(&mut out, width))?;
+        out.finish()?;
+        Ok(report)
+    })
+}
+
+/// Reads one reader after the other.
+struct ChainAll<R, I> {
+    current: Option<R>,
+    rest: I,
+}
+
+impl<R: Read, I: Iterator<Item = R>> Read for ChainAll<R, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(current) = &mut self.current {
+            match current.read(buf)? {
+                0 if !buf.is_empty() => self.current = self.rest.next(),
+                read => return Ok(read),
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Writes the samples of the `data` chunk, taken from `samples` except for the segments, whose
+/// decrypted samples are written to it in order.
+struct Splicer<'a, R, W: ?Sized> {
+    samples: R,
+    writer: &'a mut W,
+    segments: &'a [Segment],
+    frame_len: u64,
+    /// The number of bytes of samples written.
+    position: u64,
+    /// The number of bytes of the current segment which are yet to be written.
+    left: u64,
+    /// The next segment.
+    next: usize,
+}
+
+impl<'a, R: Read, W: Write + ?Sized> Splicer<'a, R, W> {
+    fn new(samples: R, writer: &'a mut W, segments: &'a [Segment], frame_len: u64) -> Self {
+        Splicer { samples, writer, segments, frame_len, position: 0, left: 0, next: 0 }
+    }
+
+    /// Writes the samples after the last segment.
+    fn finish(mut self) -> Result<()> {
+        if self.left > 0 || self.next < self.segments.len() {
+            return Err(Error::MalformedCiphertext("the segments are truncated".to_string()));
+        }
+        io::copy(&mut self.samples, self.writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read, W: Write + ?Sized> Write for Splicer<'_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            let Some(segment) = self.segments.get(self.next) else {
+                return Err(io::Error::other(Error::MalformedCiphertext("the segments are too long".to_string())));
+            };
+            // The samples up to the segment.
+            let start = segment.start * self.frame_len;
+            copy_exact(&mut self.samples, self.writer, start - self.position).map_err(io::Error::other)?;
+            self.position = start;
+            self.left = segment.frames * self.frame_len;
+            self.next += 1;
+        }
+        let len = (buf.len() as u64).min(self.left) as usize;
+        self.writer.write_all(&buf[..len])?;
+        // The silence in place of the segment.
+        copy_exact(&mut self.samples, &mut io::sink(), len as u64).map_err(io::Error::other)?;
+        self.position += len as u64;
+        self.left -= len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{decrypt_audio_stream, encrypt_audio_stream, AudioOptions},
+        encryption::{gen_key, Precision},
+        riff::{WavFormat, WAVE_FORMAT_PCM},
+        stream::Unseekable,
+    };
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let ranges = ["0.5-1.0", "0.1-0.2", "0.15-0.3", "0.9-5"].map(|range| range.parse::<TimeRange>().unwrap());
+        let segments = segments_from_ranges(&ranges, 100, 200).unwrap();
+        assert_eq!(segments, [Segment { start: 10, frames: 20 }, Segment { start: 50, frames: 150 }]);
+        assert_eq!(segments_from_bytes(&segments_to_bytes(&segments)).unwrap(), segments);
+
+        let outside = ["3-4".parse::<TimeRange>().unwrap()];
+        assert!(matches!(segments_from_ranges(&outside, 100, 200), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn segments_roundtrip_with_and_without_seeking() {
+        // 24-bit mono with an odd number of frames, so the `data` chunk is padded.
+        let format = WavFormat {
+            audio_format: WAVE_FORMAT_PCM,
+            channel_count: 1,
+            sampling_rate: 1000,
+            bytes_per_second: 3000,
+            bytes_per_sample: 3,
+            bits_per_sample: 24,
+        };
+        let data_len = 3 * 999;
+        let mut wav = Vec::new();
+        write_wav_chunks(&mut wav, &format.to_fmt(), &[], data_len, 0).unwrap();
+        wav.extend((0..data_len).map(|i| (i * 13 % 256) as u8));
+        wav.push(0);
+
+        let key = Key::Float(gen_key(4, false).unwrap());
+        let mode = Mode::Float(Precision::Single);
+        let ranges = ["0-0.1", "0.25-0.5", "0.9-1.0"].map(|range| range.parse().unwrap()).to_vec();
+        let options = AudioOptions { ranges, ..AudioOptions::default() };
+        let method = SolutionMethod::Direct;
+
+        let mut encrypted = Vec::new();
+        encrypt_audio_stream(&mut io::Cursor::new(&wav), &mut encrypted, &key, mode, &options).unwrap();
+        let mut buffered = Vec::new();
+        encrypt_audio_stream(&mut Unseekable(wav.as_slice()), &mut buffered, &key, mode, &options).unwrap();
+        assert_eq!(buffered, encrypted);
+
+        let mut decrypted = Vec::new();
+        decrypt_audio_stream(&mut io::Cursor::new(&encrypted), &mut decrypted, &key, &method).unwrap();
+        assert_eq!(decrypted, wav);
+        let mut decrypted = Vec::new();
+        decrypt_audio_stream(&mut Unseekable(encrypted.as_slice()), &mut decrypted, &key, &method).unwrap();
+        assert_eq!(decrypted, wav);
+    }
+}
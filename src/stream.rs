@@ -0,0 +1,342 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    encryption::{Key, Mode},
+    error::{Error, Result},
+    text::{encrypt_bytes, join_bits, split_bits, Decryptor},
+};
+
+/// The (minimal) number of columns of the data matrix encrypted at once.
+pub const STREAM_COLUMNS: usize = 1024;
+/// The length of the trailer which holds the length of a payload of unknown length.
+const TRAILER_LEN: usize = 8;
+
+/// The number of columns encrypted at once. At least as many as the key has, so multiplying
+/// the factors of the key for every block does not dominate, and even, so that every block
+/// holds whole bytes.
+fn block_columns(n: usize) -> usize {
+    STREAM_COLUMNS.max(n.next_multiple_of(2))
+}
+
+/// The number of matrix elements a byte of the payload turns into.
+pub fn symbols_per_byte(mode: Mode) -> usize {
+    match mode {
+        Mode::Float(_) => 2,
+        Mode::Modular => 1,
+    }
+}
+
+/// The number of encrypted numbers a payload of `length` bytes turns into: its symbols padded
+/// to whole columns of `chunk_size` elements.
+pub fn encrypted_numbers(length: u64, mode: Mode, chunk_size: u64) -> Option<u64> {
+    let symbols = length.checked_mul(symbols_per_byte(mode) as u64)?;
+    symbols.div_ceil(chunk_size.max(1)).checked_mul(chunk_size)
+}
+
+/// Reads until `buf` is full or the reader is exhausted and returns the number of bytes read.
+pub fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// ## Encrypt stream
+/// Encrypts everything `reader` yields with the key in the given mode, `block_columns` columns
+/// of the data matrix at a time, and writes the encrypted numbers (`mode.bits() / 8` big-endian
+/// bytes each) to `writer`. Returns the number of bytes encrypted.
+///
+/// Only the last block is padded, so the result is the same as encrypting everything at once,
+/// but the memory used does not depend on the length of the input.
+pub fn encrypt_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    key: &Key,
+    mode: Mode,
+) -> Result<u64> {
+    let block_len = key.n() * block_columns(key.n()) / symbols_per_byte(mode);
+    let mut block = vec![0; block_len];
+    let mut total = 0;
+
+    loop {
+        let read = read_full(reader, &mut block)?;
+        if read == 0 {
+            break;
+        }
+
+        let numbers = encrypt_bytes(&block[..read], key, mode)?;
+        writer.write_all(&numbers_to_bytes(&numbers, mode))?;
+        total += read as u64;
+
+        if read < block_len {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
+/// ## Decrypt stream
+/// Decrypts the encrypted numbers written by `encrypt_stream`, block by block with the same
+/// decryptor, and writes the first `length` decrypted bytes to `writer`. Anything `reader`
+/// yields after the encrypted numbers is ignored.
+///
+/// If the length is not known, the encrypted numbers have to be followed by a trailer with the
+/// length (big-endian `u64`), which then has to be the end of the input.
+pub fn decrypt_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    decryptor: &mut Decryptor,
+    length: Option<u64>,
+) -> Result<u64> {
+    let (n, mode) = (decryptor.n(), decryptor.mode());
+    let number_len = mode.bits() / 8;
+    let block_numbers = n * block_columns(n);
+    let block_len = block_numbers * number_len;
+    let spb = symbols_per_byte(mode);
+
+    let Some(length) = length else {
+        return decrypt_stream_with_trailer(reader, writer, decryptor);
+    };
+
+    let too_short = || Error::MalformedCiphertext(format!("the body is too short for a payload of {length} bytes"));
+    let mut numbers_left = encrypted_numbers(length, mode, n as u64).ok_or_else(too_short)?;
+    let mut bytes_left = length;
+    let mut block = vec![0; block_len];
+
+    while numbers_left > 0 {
+        let count = numbers_left.min(block_numbers as u64) as usize;
+        let block = &mut block[..count * number_len];
+        if read_full(reader, block)? < block.len() {
+            return Err(too_short());
+        }
+
+        let out_len = bytes_left.min((count / spb) as u64);
+        let bytes = decryptor.decrypt(bytes_to_numbers(block, mode), out_len as usize)?;
+        writer.write_all(&bytes)?;
+
+        numbers_left -= count as u64;
+        bytes_left -= out_len;
+    }
+
+    Ok(length)
+}
+
+/// `decrypt_stream` for payloads whose length is in the trailer. A block is only decrypted in
+/// full once it is certain that more than the trailer follows it, since the last one is padded.
+fn decrypt_stream_with_trailer<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    decryptor: &mut Decryptor,
+) -> Result<u64> {
+    let (n, mode) = (decryptor.n(), decryptor.mode());
+    let number_len = mode.bits() / 8;
+    let block_numbers = n * block_columns(n);
+    let block_len = block_numbers * number_len;
+    let spb = symbols_per_byte(mode);
+
+    let mut buf = Vec::with_capacity(block_len + TRAILER_LEN + 1);
+    let mut written = 0u64;
+    loop {
+        let start = buf.len();
+        buf.resize(block_len + TRAILER_LEN + 1, 0);
+        let read = read_full(reader, &mut buf[start..])?;
+        buf.truncate(start + read);
+        if buf.len() <= block_len + TRAILER_LEN {
+            break;
+        }
+
+        let bytes = decryptor.decrypt(bytes_to_numbers(&buf[..block_len], mode), block_numbers / spb)?;
+        writer.write_all(&bytes)?;
+        written += bytes.len() as u64;
+        buf.drain(..block_len);
+    }
+
+    if buf.len() < TRAILER_LEN {
+        return Err(Error::MalformedCiphertext("missing length trailer".to_string()));
+    }
+    let (body, trailer) = buf.split_at(buf.len() - TRAILER_LEN);
+    let length = u64::from_be_bytes(trailer.try_into().unwrap());
+
+    let count = body.len() / number_len;
+    let last_len = length.checked_sub(written).filter(|&last| {
+        body.len().is_multiple_of(number_len)
+            && count.is_multiple_of(n)
+            && encrypted_numbers(last, mode, n as u64) == Some(count as u64)
+    });
+    let Some(last_len) = last_len else {
+        return Err(Error::MalformedCiphertext(format!(
+            "the body does not hold a payload of {length} bytes"
+        )));
+    };
+
+    let bytes = decryptor.decrypt(bytes_to_numbers(body, mode), last_len as usize)?;
+    writer.write_all(&bytes)?;
+
+    Ok(length)
+}
+
+/// The big-endian bytes of the encrypted numbers.
+pub fn numbers_to_bytes(numbers: &[u64], mode: Mode) -> Vec<u8> {
+    numbers
+        .iter()
+        .flat_map(|&x| split_bits(x, mode.bits(), 8))
+        .map(|x| x as u8)
+        .collect()
+}
+
+/// The inverse of `numbers_to_bytes`.
+pub fn bytes_to_numbers(bytes: &[u8], mode: Mode) -> Vec<u64> {
+    bytes
+        .chunks_exact(mode.bits() / 8)
+        .map(|number| join_bits(&number.iter().map(|&x| x as u64).collect::<Vec<_>>(), 8))
+        .collect()
+}
+
+/// ## Sections
+/// Parts of one seekable reader which are read alternately, e.g. the samples of a `.wav` file
+/// and a chunk after them, every part with its own position. The reader only seeks when
+/// another part was read in between.
+pub struct Sections<R> {
+    /// The reader and its position.
+    inner: RefCell<(R, u64)>,
+}
+
+impl<R: Read + Seek> Sections<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let position = reader.stream_position()?;
+        Ok(Sections { inner: RefCell::new((reader, position)) })
+    }
+
+    /// The `len` bytes from `start` on.
+    pub fn section(&self, start: u64, len: u64) -> Section<'_, R> {
+        Section { inner: &self.inner, position: start, end: start + len }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().0
+    }
+}
+
+/// A part of `Sections`.
+pub struct Section<'a, R> {
+    inner: &'a RefCell<(R, u64)>,
+    position: u64,
+    end: u64,
+}
+
+impl<R: Read + Seek> Read for Section<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = (self.end - self.position).min(buf.len() as u64) as usize;
+        if left == 0 {
+            return Ok(0);
+        }
+        let (reader, position) = &mut *self.inner.borrow_mut();
+        if *position != self.position {
+            *position = reader.seek(SeekFrom::Start(self.position))?;
+        }
+        let read = reader.read(&mut buf[..left])?;
+        self.position += read as u64;
+        *position = self.position;
+        Ok(read)
+    }
+}
+
+/// A reader which can not seek, like the standard input.
+#[cfg(test)]
+pub(crate) struct Unseekable<R>(pub R);
+
+#[cfg(test)]
+impl<R: Read> Read for Unseekable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl<R> Seek for Unseekable<R> {
+    fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::{gen_key, gen_modular_key, Precision},
+        SolutionMethod,
+    };
+
+    /// Encrypts the bytes with `encrypt_stream`, followed by the trailer if the length is not
+    /// passed to `decrypt_stream`, and decrypts them from a reader which can not seek.
+    fn roundtrip(bytes: &[u8], key: &Key, mode: Mode, known_length: bool) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        let read = encrypt_stream(&mut Unseekable(bytes), &mut encrypted, key, mode).unwrap();
+        assert_eq!(read, bytes.len() as u64);
+        let numbers = encrypted_numbers(read, mode, key.n() as u64).unwrap();
+        assert_eq!(encrypted.len() as u64, numbers * (mode.bits() / 8) as u64);
+
+        // Blocks are only padded at the end, so the numbers are those of a single block.
+        let whole = numbers_to_bytes(&encrypt_bytes(bytes, key, mode).unwrap(), mode);
+        assert!(encrypted == whole, "{} bytes", bytes.len());
+
+        let length = known_length.then_some(read);
+        if !known_length {
+            encrypted.extend_from_slice(&read.to_be_bytes());
+        }
+        let mut decryptor = Decryptor::new(key, mode, &SolutionMethod::Direct).unwrap();
+        let mut decrypted = Vec::new();
+        let written = decrypt_stream(&mut Unseekable(encrypted.as_slice()), &mut decrypted, &mut decryptor, length).unwrap();
+        assert_eq!(written, read);
+        decrypted
+    }
+
+    #[test]
+    fn streams_roundtrip_across_blocks() {
+        let keys = [
+            (Key::Float(gen_key(3, false).unwrap()), Mode::Float(Precision::Single)),
+            (Key::Float(gen_key(3, false).unwrap()), Mode::Float(Precision::Double)),
+            (Key::Modular(gen_modular_key(3).unwrap()), Mode::Modular),
+        ];
+        for (key, mode) in keys {
+            // The bytes of a block, see `encrypt_stream`.
+            let block_len = key.n() * block_columns(key.n()) / symbols_per_byte(mode);
+            let bytes = (0..3 * block_len + 7).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+
+            for len in [0, 1, block_len - 1, block_len, block_len + 1, 2 * block_len, bytes.len()] {
+                for known_length in [true, false] {
+                    let decrypted = roundtrip(&bytes[..len], &key, mode, known_length);
+                    assert!(decrypted == bytes[..len], "{mode:?}, {len} bytes, known length: {known_length}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_streams_are_rejected() {
+        let key = Key::Float(gen_key(3, false).unwrap());
+        let mode = Mode::Float(Precision::Single);
+        let mut encrypted = Vec::new();
+        encrypt_stream(&mut &[7; 100][..], &mut encrypted, &key, mode).unwrap();
+        let decrypt = |body: &[u8], length: Option<u64>| {
+            let mut decryptor = Decryptor::new(&key, mode, &SolutionMethod::Direct).unwrap();
+            decrypt_stream(&mut &body[..], &mut Vec::new(), &mut decryptor, length)
+        };
+
+        let short = &encrypted[..encrypted.len() - 4];
+        assert!(matches!(decrypt(short, Some(100)), Err(Error::MalformedCiphertext(_))));
+        // Without the trailer the last numbers are taken for the length.
+        assert!(matches!(decrypt(&encrypted, None), Err(Error::MalformedCiphertext(_))));
+        assert!(matches!(decrypt(&encrypted[..4], None), Err(Error::MalformedCiphertext(_))));
+    }
+}
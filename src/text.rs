@@ -1,211 +1,427 @@
 use std::{
     fs::File,
-    io::{self, Read, Write},
+    io::{self, BufReader, Read, Write},
     path::Path,
 };
 
 use crate::{
+    container::{ContainerHeader, PayloadType},
     encryption::{
-        dectrypt_matrix_with_key_direct, dectrypt_matrix_with_key_iterative,
-        encrypt_matrix_with_key,
+        dectrypt_matrix_with_key_direct, dectrypt_matrix_with_key_modular, encrypt_matrix_with_key,
+        encrypt_matrix_with_key_modular, IterativeSystem, Key, Mode, Precision,
+        SolveReport,
     },
-    matrix::FloatMatrix,
+    error::{Error, Result},
+    files::Output,
+    matrix::{FloatElement, FloatMatrix, Matrix, MatrixElement, ModularMatrix, Z257},
+    stream::read_full,
     SolutionMethod,
 };
 
-/// Encrypts the given text file with the given key.
-pub fn encrypt_text_with_key(file_path: &str, key: &FloatMatrix) -> io::Result<()> {
-    // Convert the ASCII codes to 2-digit arithmetic by
-    // chopping it up into nibbles.
-    let vec = read_two_digit_text(file_path)?;
-    // println!("Input data (in two digit arithmetic):\n{vec:?}");
+/// Encrypts the given text file with the given key in the given mode.
+pub fn encrypt_text_with_key(file_path: &str, key: &Key, mode: Mode) -> Result<()> {
+    key.check_mode(mode)?;
+    let file = File::open(Path::new(file_path))?;
+    let length = file.metadata()?.len();
 
-    // Convert the vector of integers into a matrix with floating point entries
-    let data = vector_to_matrix(vec.iter().map(|&x| x as usize).collect(), key.n, |x| {
-        x as f32
-    });
-    // println!("converted to matrix form:\n{data}");
-
-    let encrypted = encrypt_matrix_with_key(key, &data);
-    // println!("encrypted:\n{encrypted}");
-
-    let encrypted_vector = matrix_to_vector(encrypted.transpose(), |x| x.to_bits() as usize);
-    // println!("vectorized:\n{encrypted:?}");
-    
-    // Split the floating point numbers' binary representations into 4-bit pieces
-    let data = encrypted_vector
-        .iter()
-        .flat_map(|&x| {
-            (0..32)
-                .step_by(4)
-                .rev()
-                .map(|i| ((x >> i) & 0xF) as u8)
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
-    // println!("encrypted data:\n{:?}", data);
-
-    // Shift all numbers by the ASCII code of 'a' so that all numbers, when converted to
-    // chars end up in the lowercase alphabet.
-    let data = data.iter().map(|x| x + 'a' as u8).collect();
-
-    write_text_file_vector(
-        format!("{}-encrypted.txt", file_path.strip_suffix(".txt").unwrap()).as_str(),
-        data,
-        Some(vec.len().to_string()),
-    )?;
+    let mut out = Output::new(format!(
+        "{}-encrypted.txt",
+        file_path.strip_suffix(".txt").unwrap_or(file_path)
+    ));
+    encrypt_text_stream(&mut BufReader::new(file), Some(length), &mut out, key, mode)?;
+    out.flush()?;
 
     Ok(())
 }
 
+/// Encrypts the text `reader` yields (`length` bytes, if known) with the given key in the given
+/// mode and writes the encrypted text to `writer`.
+pub fn encrypt_text_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    length: Option<u64>,
+    writer: &mut W,
+    key: &Key,
+    mode: Mode,
+) -> Result<()> {
+    // The text is encrypted as its UTF-8 byte stream, so any Unicode text survives the roundtrip.
+    let header = ContainerHeader::new(PayloadType::Text, length, Vec::new(), key, mode)?;
+
+    // Split the bytes of the container (the header and the encrypted numbers) into 4-bit
+    // pieces and shift them into the lowercase alphabet.
+    header.seal(key, reader, &mut LetterWriter(writer))
+}
+
 /// Decrypts the given text file with the given key.
+/// Returns the report of the iterative method (`None` for the direct one).
 pub fn decrypt_text_with_key(
     file_path: &str,
-    key: &FloatMatrix,
-    method: SolutionMethod,
-) -> io::Result<()> {
-    let s = read_text_file_contents(file_path)?;
-    // Read the header number.
-    let text_length = s[..s.find(" ").unwrap()].parse::<usize>().unwrap();
-    // Truncate the string to remove the header.
-    let s = &s[s.find(" ").unwrap() + 1..];
-
-    // Shift all numbers by the ASCII code of 'a' back
-    let vec = string_to_vector(s)
-        .iter()
-        .map(|&x| x - 'a' as usize)
-        .collect::<Vec<_>>();
-
-    // println!("v{vec:?}");
-
-    // Restore the floats from the 4-bit pieces
-    let float_bits = (0..vec.len())
-        .step_by(8)
-        .map(|i| {
-            ((vec[i] as usize) << 28)
-                + ((vec[i + 1] as usize) << 24)
-                + ((vec[i + 2] as usize) << 20)
-                + ((vec[i + 3] as usize) << 16)
-                + ((vec[i + 4] as usize) << 12)
-                + ((vec[i + 5] as usize) << 8)
-                + ((vec[i + 6] as usize) << 4)
-                + (vec[i + 7] as usize)
-        })
-        .collect();
-    // println!("v{float_bits:?}");
-
-    let data = vector_to_matrix(float_bits, key.n, |x| f32::from_bits(x as u32));
-    // println!("floated:\n{data}");
-
-    let decrypted = if let SolutionMethod::Iterative(iterations) = method {
-        dectrypt_matrix_with_key_iterative(key, data, iterations)
-    } else {
-        dectrypt_matrix_with_key_direct(key, data)
-    };
-    // println!("decrypted:\n{decrypted}");
-
-    let vec = matrix_to_vector(decrypted.transpose().round(0).clone(), |x| x as usize)
-        [..text_length]
-        .to_vec();
-    // println!("vec:\n{:?}", vec);
-
-    // Restore the ASCII codes form the 4-bites .
-    let vec = (0..vec.len())
-        .step_by(2)
-        .map(|i| ((vec[i] << 4) | vec[i + 1]) as u8)
-        .collect::<Vec<_>>();
-    // println!("d:{vec:?}");
-
-    write_text_file_vector(
-        format!("{}-decrypted.txt", file_path.strip_suffix(".txt").unwrap()).as_str(),
-        vec,
-        None,
-    )?;
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    let mut reader = BufReader::new(File::open(Path::new(file_path))?);
+
+    let mut out = Output::new(format!(
+        "{}-decrypted.txt",
+        file_path.strip_suffix(".txt").unwrap_or(file_path)
+    ));
+    let report = decrypt_text_stream(&mut reader, &mut out, key, method)?;
+    out.flush()?;
+
+    Ok(report)
+}
 
-    Ok(())
+/// Decrypts the encrypted text `reader` yields with the given key and writes the UTF-8 bytes of
+/// the text to `writer`. Returns the report of the iterative method (`None` for the direct one).
+pub fn decrypt_text_stream<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    key: &Key,
+    method: &SolutionMethod,
+) -> Result<Option<SolveReport>> {
+    let mut reader = LetterReader(reader);
+    let header = ContainerHeader::read_from(&mut reader)?;
+    header.check_payload(PayloadType::Text)?;
+    header.open(key, method, &mut reader, writer)
 }
 
-/// Returns the contents of a file as a `String`.
-pub fn read_text_file_contents(file_path: &str) -> io::Result<String> {
-    let mut file = File::open(Path::new(file_path))?;
+/// Writes every byte as two letters a-p, the upper and the lower nibble shifted by the ASCII
+/// code of 'a'.
+pub struct LetterWriter<W>(pub W);
+
+impl<W: Write> Write for LetterWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let letters = buf
+            .iter()
+            .flat_map(|&x| split_bits(x as u64, 8, 4))
+            .map(|x| x as u8 + b'a')
+            .collect::<Vec<_>>();
+        self.0.write_all(&letters)?;
+        Ok(buf.len())
+    }
 
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
 
-    Ok(buf)
+/// Reads the bytes written by `LetterWriter`.
+pub struct LetterReader<R>(pub R);
+
+impl<R: Read> Read for LetterReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut letters = vec![0; buf.len() * 2];
+        let read = read_full(&mut self.0, &mut letters)?;
+        if !read.is_multiple_of(2) {
+            return Err(io::Error::other(Error::MalformedCiphertext(
+                "the body length is not a multiple of 2".to_string(),
+            )));
+        }
+
+        for (byte, pair) in buf.iter_mut().zip(letters[..read].chunks_exact(2)) {
+            let nibbles = [letter_to_nibble(pair[0])?, letter_to_nibble(pair[1])?];
+            *byte = join_bits(&nibbles, 4) as u8;
+        }
+        Ok(read / 2)
+    }
 }
 
-/// Converts a string to a vector of integers of corresponding ASCII codes.
-fn string_to_vector(s: &str) -> Vec<usize> {
-    s.chars().map(|c| c as usize).collect::<Vec<_>>()
+/// Shifts a letter by the ASCII code of 'a' back.
+fn letter_to_nibble(letter: u8) -> io::Result<u64> {
+    match letter.checked_sub(b'a') {
+        Some(x) if x < 16 => Ok(x as u64),
+        _ => Err(io::Error::other(Error::MalformedCiphertext(
+            "the body must only contain the letters a-p".to_string(),
+        ))),
+    }
 }
 
-fn read_two_digit_text(file_path: &str) -> io::Result<Vec<u8>> {
-    Ok(
-        string_to_vector(read_text_file_contents(file_path)?.as_str())
+/// Encrypts the bytes with the key in the given mode and returns the binary representations
+/// of the encrypted numbers (`mode.bits()` bits each), column by column.
+pub fn encrypt_bytes(bytes: &[u8], key: &Key, mode: Mode) -> Result<Vec<u64>> {
+    // Convert the bytes to 2-digit arithmetic by chopping them up into nibbles.
+    let nibbles = || {
+        bytes
             .iter()
-            .flat_map(|&x| [((x >> 4) & 0xF) as u8, ((x >> 0) & 0xF) as u8])
-            .collect::<Vec<_>>(),
-    )
+            .flat_map(|&x| split_bits(x as u64, 8, 4))
+            .collect::<Vec<_>>()
+    };
+
+    match mode {
+        Mode::Float(Precision::Single) => encrypt_nibbles::<f32>(nibbles(), key.float()?),
+        Mode::Float(Precision::Double) => encrypt_nibbles::<f64>(nibbles(), key.float()?),
+        Mode::Modular => {
+            let key = key.modular()?;
+            let data = vector_to_matrix(bytes.iter().map(|&x| x as u64).collect(), key.n, Z257::new)?;
+            let encrypted = encrypt_matrix_with_key_modular(key, &data)?;
+            Ok(matrix_to_vector(encrypted.transpose(), |x| x.value() as u64))
+        }
+    }
+}
+
+/// ## Decryptor
+/// Decrypts the numbers produced by `encrypt_bytes` with a key in a mode, block after block.
+/// Everything which only depends on the key (the key in the element type of the mode and, for
+/// the iterative methods, the `IterativeSystem`) is built once, by `new`. The columns of every
+/// block decrypted iteratively are added to its report.
+pub struct Decryptor {
+    n: usize,
+    mode: Mode,
+    system: System,
+    report: Option<SolveReport>,
+}
+
+/// The key, ready to decrypt in a mode.
+enum System {
+    Single(FloatSystem<f32>),
+    Double(FloatSystem<f64>),
+    Modular(ModularMatrix),
+}
+
+/// The key, ready to decrypt nibbles with a method.
+enum FloatSystem<T: FloatElement> {
+    Direct(Matrix<T>),
+    Iterative(IterativeSystem<T>),
 }
 
-/// Converts `Vec<usize>` to a matrix with `chunk_size` rows
-pub fn vector_to_matrix(
-    vec: Vec<usize>,
+impl Decryptor {
+    pub fn new(key: &Key, mode: Mode, method: &SolutionMethod) -> Result<Self> {
+        let system = match mode {
+            Mode::Float(Precision::Single) => System::Single(FloatSystem::new(key.float()?, method)?),
+            Mode::Float(Precision::Double) => System::Double(FloatSystem::new(key.float()?, method)?),
+            Mode::Modular => {
+                let SolutionMethod::Direct = method else {
+                    return Err(Error::InvalidArgument(
+                        "modular ciphertext can only be decrypted with the direct method".to_string(),
+                    ));
+                };
+                System::Modular(key.modular()?.clone())
+            }
+        };
+        let report = match method {
+            SolutionMethod::Direct => None,
+            SolutionMethod::Iterative(options) => Some(SolveReport::new(options)),
+        };
+        Ok(Decryptor { n: key.n(), mode, system, report })
+    }
+
+    /// The size of the key.
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    /// The mode the numbers were encrypted in.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// What the iterations did so far (`None` for the direct method).
+    pub fn report(&self) -> Option<&SolveReport> {
+        self.report.as_ref()
+    }
+
+    /// The report of all the blocks decrypted.
+    pub fn into_report(self) -> Option<SolveReport> {
+        self.report
+    }
+
+    /// Decrypts the numbers (whole columns) and returns the first `length` restored bytes.
+    pub fn decrypt(&mut self, numbers: Vec<u64>, length: usize) -> Result<Vec<u8>> {
+        let nibbles = match &self.system {
+            System::Single(system) => system.decrypt_nibbles(numbers, length * 2, self.report.as_mut())?,
+            System::Double(system) => system.decrypt_nibbles(numbers, length * 2, self.report.as_mut())?,
+            System::Modular(key) => {
+                let data = vector_to_matrix(numbers, key.n, Z257::new)?;
+                let mut bytes = matrix_to_vector(dectrypt_matrix_with_key_modular(key, data)?.transpose(), |x| {
+                    x.value() as u64
+                });
+                if length > bytes.len() {
+                    return Err(Error::MalformedCiphertext(format!(
+                        "invalid length header `{length}`"
+                    )));
+                }
+                bytes.truncate(length);
+                return Ok(bytes.into_iter().map(|x| x as u8).collect());
+            }
+        };
+
+        // Restore the bytes form the 4-bit pieces.
+        Ok(nibbles
+            .chunks_exact(2)
+            .map(|pair| join_bits(pair, 4) as u8)
+            .collect())
+    }
+}
+
+/// Encrypts the vector of nibbles with the key (converted to `T`) and returns the binary
+/// representations of the encrypted numbers, column by column.
+pub fn encrypt_nibbles<T: FloatElement>(nibbles: Vec<u64>, key: &FloatMatrix) -> Result<Vec<u64>> {
+    let key = key.map(|x| T::from_f64(x.to_f64()));
+
+    // Convert the vector of integers into a matrix with floating point entries
+    let data = vector_to_matrix(nibbles, key.n, |x| T::from_f64(x as f64))?;
+    // println!("converted to matrix form:\n{data}");
+
+    let encrypted = encrypt_matrix_with_key(&key, &data)?;
+    // println!("encrypted:\n{encrypted}");
+
+    Ok(matrix_to_vector(encrypted.transpose(), |x| x.to_bits_u64()))
+}
+
+impl<T: FloatElement> FloatSystem<T> {
+    /// Converts the key to `T` (and builds the iterative system, if the method is iterative).
+    fn new(key: &FloatMatrix, method: &SolutionMethod) -> Result<Self> {
+        let key = key.map(|x| T::from_f64(x.to_f64()));
+        match method {
+            SolutionMethod::Direct => Ok(FloatSystem::Direct(key)),
+            SolutionMethod::Iterative(options) => {
+                Ok(FloatSystem::Iterative(IterativeSystem::new(&key, options)?))
+            }
+        }
+    }
+
+    /// Decrypts the binary representations of numbers produced by `encrypt_nibbles` and returns
+    /// the first `length` restored nibbles. The columns solved iteratively are added to the report.
+    fn decrypt_nibbles(&self, float_bits: Vec<u64>, length: usize, report: Option<&mut SolveReport>) -> Result<Vec<u64>> {
+        let n = match self {
+            FloatSystem::Direct(key) => key.n,
+            FloatSystem::Iterative(system) => system.n(),
+        };
+        let data = vector_to_matrix(float_bits, n, T::from_bits_u64)?;
+        // println!("floated:\n{data}");
+
+        let decrypted = match self {
+            FloatSystem::Direct(key) => dectrypt_matrix_with_key_direct(key, data)?,
+            FloatSystem::Iterative(system) => {
+                let (decrypted, columns) = system.solve(data)?;
+                if let Some(report) = report {
+                    report.record(system.omega(), &columns);
+                }
+                decrypted
+            }
+        };
+        // println!("decrypted:\n{decrypted}");
+
+        // Round the floats to the nearest integers.
+        let mut vec = matrix_to_vector(decrypted.transpose().round(0), |x| x.to_f64() as u64);
+        if length > vec.len() {
+            return Err(Error::MalformedCiphertext(format!(
+                "invalid length header `{length}`"
+            )));
+        }
+        vec.truncate(length);
+
+        Ok(vec)
+    }
+}
+
+/// Splits the lower `width` bits of `x` into `width / piece` pieces of `piece` bits each,
+/// starting from the most significant one.
+pub fn split_bits(x: u64, width: usize, piece: usize) -> impl Iterator<Item = u64> {
+    (0..width)
+        .step_by(piece)
+        .rev()
+        .map(move |i| (x >> i) & ((1 << piece) - 1))
+}
+
+/// Glues the `piece`-bit pieces back together, the inverse of `split_bits`.
+pub fn join_bits(pieces: &[u64], piece: usize) -> u64 {
+    pieces.iter().fold(0, |acc, &x| (acc << piece) | x)
+}
+
+/// Converts `Vec<u64>` to a matrix with `chunk_size` rows
+pub fn vector_to_matrix<T: MatrixElement>(
+    vec: Vec<u64>,
     chunk_size: usize,
-    convertor: fn(usize) -> f32,
-) -> FloatMatrix {
+    convertor: fn(u64) -> T,
+) -> Result<Matrix<T>> {
     let length = vec.len();
 
+    if chunk_size == 0 {
+        return Err(Error::DimensionMismatch {
+            expected: (1, length),
+            found: (0, length),
+        });
+    }
+    if length == 0 {
+        return Ok(Matrix::zero(chunk_size, 0));
+    }
+
     let mut vec = vec;
-    if length % chunk_size != 0 {
+    if !length.is_multiple_of(chunk_size) {
         vec.append(&mut vec![
             vec[length - 1];
             chunk_size - (length % chunk_size)
         ]);
     }
 
-    // The vector of vectors of the char codes of the padded input string.
-    FloatMatrix::from(
-        (0..vec.len())
-            .step_by(chunk_size)
-            .map(|i| {
-                vec[i..i + chunk_size]
-                    .iter()
-                    .map(|&c| convertor(c))
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>(),
-    )
-    .transpose()
+    // Every `chunk_size` consecutive elements of the padded input form a row, so the
+    // padded vector already is the row-major buffer of the transposed matrix.
+    let columns = vec.len() / chunk_size;
+    Matrix::from_vec(columns, chunk_size, vec.into_iter().map(convertor).collect())
+        .map(|matrix| matrix.transpose())
 }
 
 /// Converts a matrix to a vector while applying a `convertor` function to every element.
-pub fn matrix_to_vector(matrix: FloatMatrix, convertor: fn(f32) -> usize) -> Vec<usize> {
+pub fn matrix_to_vector<T: MatrixElement>(matrix: Matrix<T>, convertor: fn(T) -> u64) -> Vec<u64> {
     matrix
         .data
-        .iter()
-        .flat_map(|x| x.iter().map(|&e| convertor(e)).collect::<Vec<_>>())
+        .into_iter()
+        .map(convertor)
         .collect()
 }
 
-/// Writes the contents of a byte vector to the file as ASCII Symbols
-pub fn write_text_file_vector(
-    file_path: &str,
-    data: Vec<u8>,
-    header: Option<String>,
-) -> io::Result<()> {
-    let mut file = File::create(Path::new(file_path))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{gen_key, gen_modular_key};
+
+    #[test]
+    fn unicode_text_roundtrips() {
+        let text = "naïve café, 日本語, გამარჯობა 👋🏽 and a 🦀";
+        let keys = [
+            (Key::Float(gen_key(5, false).unwrap()), Mode::Float(Precision::Single)),
+            (Key::Float(gen_key(5, false).unwrap()), Mode::Float(Precision::Double)),
+            (Key::Modular(gen_modular_key(5).unwrap()), Mode::Modular),
+        ];
+        for (key, mode) in keys {
+            let mut encrypted = Vec::new();
+            encrypt_text_stream(&mut text.as_bytes(), Some(text.len() as u64), &mut encrypted, &key, mode).unwrap();
+            assert!(encrypted.iter().all(|c| (b'a'..=b'p').contains(c)), "{mode:?}");
+
+            let mut decrypted = Vec::new();
+            decrypt_text_stream(&mut &encrypted[..], &mut decrypted, &key, &SolutionMethod::Direct).unwrap();
+            assert_eq!(String::from_utf8(decrypted).unwrap(), text, "{mode:?}");
+        }
+    }
 
-    let out = data.iter().map(|&c| c as char).collect::<String>();
+    #[test]
+    fn double_precision_bytes_roundtrip() {
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        let key = Key::Float(gen_key(6, false).unwrap());
+        let mode = Mode::Float(Precision::Double);
 
-    // println!("{}", &out[..data_length]);
+        // The bit patterns of the f64s need all 64 bits, even where `usize` has 32.
+        let numbers = encrypt_bytes(&bytes, &key, mode).unwrap();
+        assert!(numbers.iter().any(|&x| x > u32::MAX as u64));
 
-    if let Some(header) = header {
-        file.write(format!("{} {}", header, out).as_bytes())?
-    } else {
-        file.write(out.as_bytes())?
-    };
+        let mut decryptor = Decryptor::new(&key, mode, &SolutionMethod::Direct).unwrap();
+        assert_eq!(decryptor.decrypt(numbers, bytes.len()).unwrap(), bytes);
+    }
 
-    Ok(())
+    #[test]
+    fn rejected_files_leave_no_output() {
+        let path = std::env::temp_dir().join(format!("cp1-{}-garbage.txt", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        std::fs::write(&path, "not a container").unwrap();
+
+        let key = Key::Float(gen_key(3, false).unwrap());
+        let decrypted = decrypt_text_with_key(&path, &key, &SolutionMethod::Direct);
+        assert!(matches!(decrypted, Err(Error::MalformedCiphertext(_))));
+        assert!(encrypt_text_with_key(&path, &key, Mode::Modular).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let stem = path.strip_suffix(".txt").unwrap();
+        assert!(!Path::new(&format!("{stem}-decrypted.txt")).exists());
+        assert!(!Path::new(&format!("{stem}-encrypted.txt")).exists());
+    }
 }